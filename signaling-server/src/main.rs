@@ -0,0 +1,164 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{net::{TcpListener, TcpStream}, sync::mpsc};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tracing::{info, warn};
+
+/// Self-hostable stand-in for the hosted signaling hub, speaking the same JSON protocol as `wasm_peers_rtc::signaling`.
+#[derive(Parser)]
+struct Args {
+    /// Address to accept websocket connections on
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    bind: SocketAddr,
+}
+
+type ConnectionId = String;
+
+/// Messages sent by games. Relays carry no `src`; the hub fills it in.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action")]
+enum Incoming {
+    #[serde(rename = "refresh")]
+    Refresh,
+    #[serde(rename = "register")]
+    Register {
+        #[serde(flatten)]
+        entry: ServerEntry
+    },
+    #[serde(rename = "unregister")]
+    Unregister,
+    #[serde(rename = "relay")]
+    Relay {
+        dst: ConnectionId,
+        data: Value
+    }
+}
+
+/// Messages sent to games.
+#[derive(Serialize)]
+#[serde(tag = "action")]
+enum Outgoing<'a> {
+    #[serde(rename = "list")]
+    List {
+        servers: &'a HashMap<ConnectionId, ServerEntry>
+    },
+    #[serde(rename = "relay")]
+    Relay {
+        src: &'a str,
+        dst: &'a str,
+        data: Value
+    }
+}
+
+/// Whatever the server advertises about itself (name, game, player counts, ...), passed on as is.
+type ServerEntry = Map<String, Value>;
+
+#[derive(Default)]
+struct Hub {
+    next_connection_id: u64,
+    connections: HashMap<ConnectionId, mpsc::UnboundedSender<Message>>,
+    servers: HashMap<ConnectionId, ServerEntry>
+}
+
+impl Hub {
+    fn send(&self, dst: &str, msg: &Outgoing) -> bool {
+        let Some(connection) = self.connections.get(dst) else {
+            return false;
+        };
+        let text = serde_json::to_string(msg).expect("Outgoing messages are always serializable");
+        connection.send(Message::Text(text)).is_ok()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let listener = TcpListener::bind(args.bind).await.expect("Failed to bind signaling server");
+    info!("Signaling server listening on ws://{}", args.bind);
+
+    let hub = Arc::new(Mutex::new(Hub::default()));
+    while let Ok((stream, addr)) = listener.accept().await {
+        tokio::spawn(handle_connection(hub.clone(), stream, addr));
+    }
+}
+
+async fn handle_connection(hub: Arc<Mutex<Hub>>, stream: TcpStream, addr: SocketAddr) {
+    let websocket = match accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!("{}: websocket handshake failed: {}", addr, e);
+            return;
+        }
+    };
+    let (mut sink, mut stream) = websocket.split();
+
+    // Register the connection and greet it with the current server list, which it may ask for again later
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let connection_id = {
+        let mut hub = hub.lock().unwrap();
+        hub.next_connection_id += 1;
+        let connection_id = format!("conn-{}", hub.next_connection_id);
+        hub.connections.insert(connection_id.clone(), sender);
+        hub.send(&connection_id, &Outgoing::List { servers: &hub.servers });
+        connection_id
+    };
+    info!("{}: connected from {}", connection_id, addr);
+
+    tokio::spawn(async move {
+        while let Some(msg) = receiver.recv().await {
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    while let Some(msg) = stream.next().await {
+        let text = match msg {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("{}: {}", connection_id, e);
+                break;
+            }
+        };
+        match serde_json::from_str::<Incoming>(&text) {
+            Ok(Incoming::Refresh) => {
+                let hub = hub.lock().unwrap();
+                hub.send(&connection_id, &Outgoing::List { servers: &hub.servers });
+            }
+            Ok(Incoming::Register { entry }) => {
+                let entry_json = Value::Object(entry.clone());
+                info!("{}: registered server {}", connection_id, entry_json);
+                hub.lock().unwrap().servers.insert(connection_id.clone(), entry);
+            }
+            Ok(Incoming::Unregister) => unregister(&mut hub.lock().unwrap(), &connection_id),
+            Ok(Incoming::Relay { dst, data }) => {
+                let relay = Outgoing::Relay { src: &connection_id, dst: &dst, data };
+                if !hub.lock().unwrap().send(&dst, &relay) {
+                    warn!("{}: dropping relay to unknown connection {}", connection_id, dst);
+                }
+            }
+            Err(e) => warn!("{}: ignoring malformed message {:?}: {}", connection_id, text, e),
+        }
+    }
+
+    let mut hub = hub.lock().unwrap();
+    hub.connections.remove(&connection_id);
+    unregister(&mut hub, &connection_id);
+    info!("{}: disconnected", connection_id);
+}
+
+fn unregister(hub: &mut Hub, connection_id: &str) {
+    if let Some(server) = hub.servers.remove(connection_id) {
+        let name = server.get("name").cloned().unwrap_or_default();
+        info!("{}: unregistered server {}", connection_id, name);
+    }
+}
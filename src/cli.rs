@@ -0,0 +1,156 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use bevy::log::Level;
+use clap::{error::ErrorKind, parser::ValueSource, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use serde_json::{Map, Value};
+
+use crate::{player::BanList, rate_limit::RateLimitConfig, wasm_peers_rtc::config::NetworkConfig, world::WorldConfig};
+
+pub const DEFAULT_TICK_RATE: u32 = 60;
+
+#[derive(Parser)]
+#[command(about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a dedicated server and register it with the signaling server
+    Server(ServerArgs),
+}
+
+#[derive(Args)]
+pub struct ServerArgs {
+    /// JSON file with settings keyed by their long flag names, e.g. {"server-name": "eu-1", "max-players": 16}.
+    /// Flags given on the command line take precedence
+    #[arg(long = "config", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// How many times per second the simulation runs
+    #[arg(long = "tick-rate", default_value_t = DEFAULT_TICK_RATE, value_parser = clap::value_parser!(u32).range(1..))]
+    pub tick_rate: u32,
+    /// Most verbose level to log: error, warn, info, debug or trace
+    #[arg(long = "log-level", default_value_t = Level::INFO)]
+    pub log_level: Level,
+    /// File keeping banned usernames and sessions across restarts
+    #[arg(long = "ban-list", value_name = "FILE", default_value = "bans.json")]
+    pub ban_list: PathBuf,
+    #[command(flatten)]
+    pub world: WorldConfig,
+    #[command(flatten)]
+    pub network: NetworkConfig,
+    #[command(flatten)]
+    pub rate_limits: RateLimitConfig,
+}
+
+impl Cli {
+    // Parses the command line, filling in whatever it leaves out from the config file. Exits on errors
+    pub fn load() -> Cli {
+        let mut args: Vec<String> = std::env::args().collect();
+        let matches = Cli::command().get_matches_from(&args);
+        if let Some(("server", server_matches)) = matches.subcommand() {
+            if let Some(path) = server_matches.get_one::<PathBuf>("config") {
+                let command = Cli::command();
+                match config_file_args(path, command.find_subcommand("server").unwrap(), server_matches) {
+                    Ok(file_args) => args.extend(file_args),
+                    Err(e) => Cli::command().error(ErrorKind::InvalidValue, e).exit(),
+                }
+            }
+        }
+        let matches = Cli::command().get_matches_from(&args);
+        Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+}
+
+impl ServerArgs {
+    // Exits when the ban list exists but can't be used, rather than risk overwriting it
+    pub fn load_bans(&self) -> BanList {
+        BanList::load(self.ban_list.clone()).unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit())
+    }
+}
+
+// Turns the config file into flags, skipping the ones already given on the command line
+fn config_file_args(path: &Path, server: &clap::Command, matches: &clap::ArgMatches) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Can't read config file `{}`: {}", path.display(), e))?;
+    let settings: Map<String, Value> = serde_json::from_str(&contents).map_err(|e| format!("Invalid config file `{}`: {}", path.display(), e))?;
+    let mut args = Vec::new();
+    for (key, value) in settings {
+        let arg = server.get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()) && key != "config")
+            .ok_or_else(|| format!("Unknown setting `{}` in config file `{}`", key, path.display()))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                // Switches are given or not, other flags take the value
+                Value::Bool(value) if !arg.get_action().takes_values() => {
+                    if value {
+                        args.push(format!("--{}", key));
+                    }
+                    continue;
+                }
+                Value::Bool(value) => value.to_string(),
+                value => return Err(format!("Setting `{}` in config file `{}` must be a string, number or boolean, not {}", key, path.display(), value)),
+            };
+            args.push(format!("--{}={}", key, value));
+        }
+    }
+    Ok(args)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction};
+
+    use super::*;
+
+    // Writes `settings` to a config file and turns it into flags against `command_line`
+    fn file_args(command: clap::Command, name: &str, settings: &str, command_line: &[&str]) -> Result<Vec<String>, String> {
+        let path = std::env::temp_dir().join(format!("cli-{}-{}.json", std::process::id(), name));
+        fs::write(&path, settings).unwrap();
+        let matches = command.clone().try_get_matches_from(["game", "server"].iter().chain(command_line)).unwrap();
+        let (_, server_matches) = matches.subcommand().unwrap();
+        let args = config_file_args(&path, command.find_subcommand("server").unwrap(), server_matches);
+        fs::remove_file(&path).unwrap();
+        args
+    }
+
+    #[test]
+    fn switches_are_given_or_not_and_other_flags_take_booleans_as_values() {
+        let command = Cli::command().mut_subcommand("server", |server| server.arg(Arg::new("headless").long("headless").action(ArgAction::SetTrue)));
+        assert_eq!(file_args(command.clone(), "switch-on", r#"{"headless": true, "password": true}"#, &[]).unwrap(), ["--headless", "--password=true"]);
+        assert_eq!(file_args(command, "switch-off", r#"{"headless": false, "password": false}"#, &[]).unwrap(), ["--password=false"]);
+    }
+
+    #[test]
+    fn arrays_repeat_the_flag() {
+        let args = file_args(Cli::command(), "array", r#"{"ice-server": ["stun:a.example", "turn:b.example"], "max-players": 16}"#, &[]).unwrap();
+        assert_eq!(args, ["--ice-server=stun:a.example", "--ice-server=turn:b.example", "--max-players=16"]);
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_the_file() {
+        let args = file_args(Cli::command(), "precedence", r#"{"server-name": "eu-1", "max-players": 16}"#, &["--max-players", "4"]).unwrap();
+        assert_eq!(args, ["--server-name=eu-1"]);
+        let matches = Cli::command().try_get_matches_from(["game", "server", "--max-players", "4"].into_iter().chain(args.iter().map(String::as_str))).unwrap();
+        let Command::Server(server) = Cli::from_arg_matches(&matches).unwrap().command;
+        assert_eq!((server.network.server_name.as_str(), server.network.max_players), ("eu-1", 4));
+    }
+
+    #[test]
+    fn rejects_unknown_settings_and_unsupported_values() {
+        let error = file_args(Cli::command(), "unknown", r#"{"config": "other.json"}"#, &[]).unwrap_err();
+        assert!(error.starts_with("Unknown setting `config`"), "{}", error);
+        let error = file_args(Cli::command(), "object", r#"{"map": {"name": "arena"}}"#, &[]).unwrap_err();
+        assert!(error.starts_with("Setting `map`"), "{}", error);
+    }
+}
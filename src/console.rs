@@ -0,0 +1,242 @@
+use std::str::FromStr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{mpsc::{self, Receiver}, Mutex};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use bevy_replicon::network_event::{EventType, server_event::{ServerEventAppExt, ToClients, SendMode}};
+use renet::ClientId;
+use serde::{Serialize, Deserialize};
+
+use crate::{player::SessionToken, Multiplayer};
+
+pub const DEFAULT_SHUTDOWN_REASON: &str = "The server is shutting down";
+// How long an admin message stays on screen
+const MESSAGE_DURATION_SECS: f32 = 8.;
+
+pub struct ConsolePlugin {
+    pub is_headless: bool
+}
+
+impl Plugin for ConsolePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<AdminCommand>();
+        app.add_server_event::<AdminMessageEvent>(EventType::Ordered);
+
+        app.add_systems(Update, say.run_if(Multiplayer::state_is_server()));
+        if !self.is_headless {
+            app.add_systems(Update, show_admin_messages.run_if(Multiplayer::state_is_playable()));
+        }
+
+        // A dedicated server is operated by typing commands into its terminal
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.is_headless {
+            app.insert_resource(ConsoleInput { lines: Mutex::new(spawn_stdin_reader()) });
+            app.add_systems(Update, read_console.run_if(Multiplayer::state_is_server()));
+        }
+    }
+}
+
+// Something an admin wants done to the running game, each plugin handles its part
+#[derive(Event, Clone, Debug)]
+pub enum AdminCommand {
+    Help,
+    Players,
+    Kick { client_id: ClientId },
+    Ban { target: BanTarget },
+    Unban { username: String },
+    UnbanSession { session: SessionToken },
+    Bans,
+    Say { text: String },
+    SpawnRate { factor: f32 },
+    RestartRound,
+    Shutdown { reason: String }
+}
+
+#[derive(Clone, Debug)]
+pub enum BanTarget {
+    Username(String),
+    Client(ClientId), // Bans the session of the client's player, whatever it's called
+    Player(Entity), // Bans the player's session, also while it's disconnected
+    PlayersNamed(String) // Bans the sessions of every player with this name, but not the name itself
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+const HELP: &str = "\
+players                 List connected and disconnected players
+kick <CLIENT_ID>        Disconnect a client, its player is removed
+ban <USERNAME>          Kick everyone playing as USERNAME and refuse them from now on
+ban-client <CLIENT_ID>  Kick a client and refuse its session from now on
+ban-player <USERNAME>   Refuse the sessions of USERNAME's players, disconnected ones too, but not the name
+bans                    List banned usernames and sessions
+unban <USERNAME>        Let USERNAME join again
+unban-session <SESSION> Let a banned session join again
+say <TEXT>              Show a message to everyone
+spawn-rate <FACTOR>     Make every enemy spawner spawn FACTOR times as often as normal
+restart                 Start a new round: enemies gone, scores reset
+shutdown [REASON]       Send everyone back to their main menu, telling them why, and exit";
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+        let args = args.trim();
+        let required = |what: &str| if args.is_empty() { Err(format!("Usage: {} <{}>", name, what)) } else { Ok(args.to_owned()) };
+        match name {
+            "help" => Ok(AdminCommand::Help),
+            "players" => Ok(AdminCommand::Players),
+            "kick" => {
+                let client_id = required("CLIENT_ID")?.parse().map_err(|e| format!("Invalid client ID `{}`: {}", args, e))?;
+                Ok(AdminCommand::Kick { client_id: ClientId::from_raw(client_id) })
+            }
+            "ban" => Ok(AdminCommand::Ban { target: BanTarget::Username(required("USERNAME")?) }),
+            "ban-client" => {
+                let client_id = required("CLIENT_ID")?.parse().map_err(|e| format!("Invalid client ID `{}`: {}", args, e))?;
+                Ok(AdminCommand::Ban { target: BanTarget::Client(ClientId::from_raw(client_id)) })
+            }
+            "ban-player" => Ok(AdminCommand::Ban { target: BanTarget::PlayersNamed(required("USERNAME")?) }),
+            "bans" => Ok(AdminCommand::Bans),
+            "unban" => Ok(AdminCommand::Unban { username: required("USERNAME")? }),
+            "unban-session" => {
+                let session = required("SESSION")?.parse().map_err(|e| format!("Invalid session `{}`: {}", args, e))?;
+                Ok(AdminCommand::UnbanSession { session })
+            }
+            "say" => Ok(AdminCommand::Say { text: required("TEXT")? }),
+            "spawn-rate" => {
+                let factor: f32 = required("FACTOR")?.parse().map_err(|e| format!("Invalid factor `{}`: {}", args, e))?;
+                if !factor.is_finite() || factor <= 0. {
+                    return Err(format!("Factor must be positive, not {}", factor));
+                }
+                Ok(AdminCommand::SpawnRate { factor })
+            }
+            "restart" => Ok(AdminCommand::RestartRound),
+            "shutdown" if args.is_empty() => Ok(AdminCommand::Shutdown { reason: DEFAULT_SHUTDOWN_REASON.to_owned() }),
+            "shutdown" => Ok(AdminCommand::Shutdown { reason: args.to_owned() }),
+            _ => Err(format!("Unknown command `{}`, try `help`", name)),
+        }
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct AdminMessageEvent {
+    pub text: String
+}
+
+// Lines typed into the terminal, read on their own thread so the frame loop never waits for input
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Resource)]
+struct ConsoleInput {
+    lines: Mutex<Receiver<String>>
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            // Stdin closing (e.g. when run as a service) just means nobody is typing
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_console(input: Res<ConsoleInput>, mut writer: EventWriter<AdminCommand>) {
+    let lines = input.lines.lock().unwrap();
+    while let Ok(line) = lines.try_recv() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(AdminCommand::Help) => info!("Commands:\n{}", HELP),
+            Ok(command) => writer.send(command),
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+fn say(mut reader: EventReader<AdminCommand>, mut writer: EventWriter<ToClients<AdminMessageEvent>>) {
+    for command in reader.read() {
+        if let AdminCommand::Say { text } = command {
+            info!("Server: {}", text);
+            writer.send(ToClients { mode: SendMode::Broadcast, event: AdminMessageEvent { text: text.to_owned() } });
+        }
+    }
+}
+
+fn show_admin_messages(
+    mut contexts: EguiContexts,
+    mut reader: EventReader<AdminMessageEvent>,
+    time: Res<Time>,
+    mut messages: Local<Vec<(String, Timer)>>
+) {
+    for evt in reader.read() {
+        messages.push((evt.text.to_owned(), Timer::from_seconds(MESSAGE_DURATION_SECS, TimerMode::Once)));
+    }
+    messages.retain_mut(|(_, timer)| !timer.tick(time.delta()).finished());
+    if messages.is_empty() {
+        return;
+    }
+    egui::Area::new("admin_messages").anchor(egui::Align2::CENTER_TOP, [0., 8.]).show(contexts.ctx_mut(), |ui| {
+        egui::Frame::popup(ui.style()).show(ui, |ui| {
+            for (text, _) in messages.iter() {
+                ui.label(format!("Server: {}", text));
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<AdminCommand, String> {
+        line.parse()
+    }
+
+    #[test]
+    fn reports_usage_for_missing_arguments() {
+        assert_eq!(parse("kick").unwrap_err(), "Usage: kick <CLIENT_ID>");
+        assert_eq!(parse("ban   ").unwrap_err(), "Usage: ban <USERNAME>");
+        assert_eq!(parse("unban-session").unwrap_err(), "Usage: unban-session <SESSION>");
+        assert_eq!(parse("spawn-rate").unwrap_err(), "Usage: spawn-rate <FACTOR>");
+        assert_eq!(parse("say").unwrap_err(), "Usage: say <TEXT>");
+    }
+
+    #[test]
+    fn parses_client_ids_and_sessions() {
+        assert!(matches!(parse("kick 7"), Ok(AdminCommand::Kick { client_id }) if client_id == ClientId::from_raw(7)));
+        assert!(matches!(parse(" ban-client  7 "), Ok(AdminCommand::Ban { target: BanTarget::Client(client_id) }) if client_id == ClientId::from_raw(7)));
+        assert!(matches!(parse("unban-session 18446744073709551615"), Ok(AdminCommand::UnbanSession { session: u64::MAX })));
+        assert!(parse("kick bob").unwrap_err().starts_with("Invalid client ID `bob`"));
+        assert!(parse("ban-client -1").unwrap_err().starts_with("Invalid client ID `-1`"));
+        assert!(parse("unban-session 18446744073709551616").unwrap_err().starts_with("Invalid session"));
+    }
+
+    #[test]
+    fn spawn_rate_must_be_positive_and_finite() {
+        assert!(matches!(parse("spawn-rate 0.5"), Ok(AdminCommand::SpawnRate { factor }) if factor == 0.5));
+        for factor in ["0", "-2", "inf", "NaN"] {
+            assert!(parse(&format!("spawn-rate {}", factor)).unwrap_err().starts_with("Factor must be positive"), "{}", factor);
+        }
+        assert!(parse("spawn-rate fast").unwrap_err().starts_with("Invalid factor `fast`"));
+    }
+
+    #[test]
+    fn shutdown_reason_is_optional() {
+        assert!(matches!(parse("shutdown"), Ok(AdminCommand::Shutdown { reason }) if reason == DEFAULT_SHUTDOWN_REASON));
+        assert!(matches!(parse("shutdown  back in 5 minutes "), Ok(AdminCommand::Shutdown { reason }) if reason == "back in 5 minutes"));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert_eq!(parse("explode now").unwrap_err(), "Unknown command `explode`, try `help`");
+    }
+}
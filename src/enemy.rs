@@ -1,15 +1,16 @@
-use bevy::{prelude::*, sprite::Anchor};
+use bevy::{prelude::*, sprite::Anchor, utils::Duration};
 use bevy_replicon::replicon_core::replication_rules::{AppReplicationExt, Replication};
 use serde::{Serialize, Deserialize};
 use rand::prelude::*;
 
-use crate::{player::Player, position::Position, Multiplayer};
+use crate::{console::AdminCommand, player::Player, position::Position, world::WorldRng, Multiplayer};
 
 pub struct EnemyPlugin {}
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Update, (update_enemies, update_spawners).run_if(Multiplayer::state_is_authoritative()));
+        app.add_systems(Update, (set_spawn_rate, restart_round).run_if(Multiplayer::state_is_server()));
 
         app.add_systems(Update, added_enemies.run_if(Multiplayer::state_is_playable()));
 
@@ -20,7 +21,18 @@ impl Plugin for EnemyPlugin {
 #[derive(Component)]
 pub struct EnemySpawner {
     pub image: String,
-    pub timer: Timer
+    pub timer: Timer,
+    interval: Duration // At a spawn rate of 1, see AdminCommand::SpawnRate
+}
+
+impl EnemySpawner {
+    pub fn new(image: &str, interval_secs: f32) -> EnemySpawner {
+        EnemySpawner {
+            image: image.to_owned(),
+            timer: Timer::from_seconds(interval_secs, TimerMode::Repeating),
+            interval: Duration::from_secs_f32(interval_secs)
+        }
+    }
 }
 
 #[derive(Component, Default, Reflect, Serialize, Deserialize)]
@@ -29,10 +41,10 @@ pub struct Enemy {
     image: String
 }
 
-fn update_spawners(mut commands: Commands, mut spawners: Query<(&mut EnemySpawner, &Position)>, time: Res<Time>, players: Query<&Player>, enemies: Query<&Enemy>) {
+fn update_spawners(mut commands: Commands, mut spawners: Query<(&mut EnemySpawner, &Position)>, time: Res<Time>, players: Query<&Player>, enemies: Query<&Enemy>, mut rng: ResMut<WorldRng>) {
     for (mut spawner, position) in spawners.iter_mut() {
         let mut position = position.clone();
-        position.translation += Vec3::new(random(), random(), 0.).normalize_or_zero() * random::<f32>() * 200.;
+        position.translation += Vec3::new(rng.gen(), rng.gen(), 0.).normalize_or_zero() * rng.gen::<f32>() * 200.;
         spawner.timer.tick(time.delta());
         if spawner.timer.just_finished() {
             if enemies.iter().len() >= players.iter().len() * 100 {
@@ -47,6 +59,32 @@ fn update_spawners(mut commands: Commands, mut spawners: Query<(&mut EnemySpawne
     }
 }
 
+// Each spawner keeps its own pace relative to the others
+fn set_spawn_rate(mut reader: EventReader<AdminCommand>, mut spawners: Query<&mut EnemySpawner>) {
+    for command in reader.read() {
+        if let AdminCommand::SpawnRate { factor } = command {
+            info!("Enemies now spawn {}x as often as normal", factor);
+            for mut spawner in spawners.iter_mut() {
+                let interval = spawner.interval.div_f32(*factor);
+                spawner.timer.set_duration(interval);
+            }
+        }
+    }
+}
+
+fn restart_round(mut commands: Commands, mut reader: EventReader<AdminCommand>, enemies: Query<Entity, With<Enemy>>, mut spawners: Query<&mut EnemySpawner>) {
+    if !reader.read().any(|command| matches!(command, AdminCommand::RestartRound)) {
+        return;
+    }
+    info!("Restarting round");
+    for enemy in enemies.iter() {
+        commands.entity(enemy).despawn_recursive();
+    }
+    for mut spawner in spawners.iter_mut() {
+        spawner.timer.reset();
+    }
+}
+
 fn added_enemies(mut commands: Commands, asset_server: Res<AssetServer>, mut new_enemies: Query<(Entity, &Enemy), Added<Enemy>>) {
     for (new_entity, new_enemy) in new_enemies.iter_mut() {
         let new_image: Handle<Image> = asset_server.load(&new_enemy.image);
@@ -63,7 +101,7 @@ fn added_enemies(mut commands: Commands, asset_server: Res<AssetServer>, mut new
     }
 }
 
-fn update_enemies(mut commands: Commands, mut enemies: Query<&mut Position, With<Enemy>>, players: Query<(Entity, &Position), (With<Player>, Without<Enemy>)>, time: Res<Time>) {
+fn update_enemies(mut commands: Commands, mut enemies: Query<&mut Position, With<Enemy>>, players: Query<(Entity, &Position), (With<Player>, Without<Enemy>)>, time: Res<Time>, mut rng: ResMut<WorldRng>) {
     for mut enemy_position in enemies.iter_mut() {
         // Target nearest player
         let mut nearest = None;
@@ -84,7 +122,7 @@ fn update_enemies(mut commands: Commands, mut enemies: Query<&mut Position, With
         }
 
         // Wander
-        let delta = Vec3::new(random(), random(), 0.).normalize_or_zero();
+        let delta = Vec3::new(rng.gen(), rng.gen(), 0.).normalize_or_zero();
         enemy_position.translation += delta * time.delta_seconds() * 20.;
     }
     // Enemies stay away from each other to not get clumped
@@ -1,20 +1,34 @@
-use bevy_replicon::{ReplicationPlugins, replicon_core::replication_rules::MapNetworkEntities};
+use bevy_replicon::{ReplicationPlugins, replicon_core::replication_rules::{MapNetworkEntities, Replication}};
 use position::Position;
 use serde::{Deserialize, Serialize};
-use bevy::{prelude::*, log::{LogPlugin, Level}, window::CursorGrabMode};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::{prelude::*, log::LogPlugin, window::CursorGrabMode};
 use bevy_egui::EguiPlugin;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use enemy::EnemySpawner;
 use player_controller::{Cursor, CursorSprite, PlayerController};
 use projectile::{Projectile, ProjectileHits};
-use wasm_peers_rtc::client::WebRtcBrowser;
+#[cfg(target_arch = "wasm32")]
+use wasm_peers_rtc::{browser::WebRtcBrowserPlugin, client::{WebRtcBrowser, WebRtcClient, WebRtcClientPlugin}};
 #[cfg(target_arch = "wasm32")]
 use web_sys::window;
+#[cfg(target_arch = "wasm32")]
+use rate_limit::RateLimitConfig;
+#[cfg(not(target_arch = "wasm32"))]
+use cli::{Cli, Command};
 
 use crate::{
-    enemy::EnemyPlugin, main_menu::{MainMenuPlugin, MainMenu}, player::PlayerPlugin, player_controller::PlayerControllerPlugin, position::PositionPlugin, projectile::ProjectilePlugin, wasm_peers_rtc::{browser::WebRtcBrowserPlugin, client::WebRtcClientPlugin, server::{WebRtcServer, WebRtcServerPlugin}}, world::WorldPlugin
+    console::ConsolePlugin, enemy::EnemyPlugin, shutdown::ShutdownPlugin, main_menu::{MainMenuPlugin, MainMenu}, player::{Player, PlayerPlugin}, player_controller::PlayerControllerPlugin, position::PositionPlugin, projectile::ProjectilePlugin, rate_limit::RateLimitPlugin, wasm_peers_rtc::{config::NetworkConfig, server::{WebRtcServer, WebRtcServerPlugin}, simulator::NetworkSimulatorPlugin, stats::NetworkStatsPlugin}, world::{WorldConfig, WorldPlugin, WorldRng}
 };
 
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+mod console;
+mod shutdown;
+
 mod enemy;
 mod player;
 mod player_controller;
@@ -23,6 +37,7 @@ mod world;
 mod wasm_peers_rtc;
 mod position;
 mod main_menu;
+mod rate_limit;
 
 #[derive(States, Default, Debug, Hash, PartialEq, Eq, Clone)]
 enum Multiplayer {
@@ -79,31 +94,36 @@ pub struct PlayerInfo {
 }
 
 fn main() {
-    println!("Hello, world!");
-
     #[cfg(target_arch = "wasm32")]
     console_error_panic_hook::set_once();
 
     #[cfg(not(target_arch = "wasm32"))]
-    let headless = true;
+    let Command::Server(server_args) = Cli::load().command;
+    #[cfg(not(target_arch = "wasm32"))]
+    let bans = server_args.load_bans();
+    #[cfg(not(target_arch = "wasm32"))]
+    let (headless, network_config, world_config, rate_limit_config, log_level) = (true, server_args.network, server_args.world, server_args.rate_limits, server_args.log_level);
 
     #[cfg(target_arch = "wasm32")]
-    let headless = {
+    let (headless, query) = {
         let mut headless = false;
+        let mut query = String::new();
         if let Some(window) = window() {
             if let Some(document) = window.document() {
                 if let Some(location) = document.location() {
                     if let Ok(search) = location.search() {
                         if search.len() > 1 {
-                            let query = search.split_at(1).1;
-                            headless = query == "headless";
+                            query = search.split_at(1).1.to_owned();
+                            headless = query.split('&').any(|param| param == "headless");
                         }
                     }
                 }
             }
         }
-        headless
+        (headless, query)
     };
+    #[cfg(target_arch = "wasm32")]
+    let (world_config, rate_limit_config, log_level) = (WorldConfig::default(), RateLimitConfig::default(), bevy::log::Level::INFO);
 
     #[cfg(debug_assertions)]
     let canvas = None;
@@ -111,9 +131,26 @@ fn main() {
     let canvas = Some("#canvas".to_string());
 
     let mut app = App::new();
-    app.add_plugins(LogPlugin {filter: "wgpu_hal=off,wgpu_core=off,some-game=info".to_string(), level: Level::INFO});
+    app.add_plugins(LogPlugin {filter: format!("wgpu_hal=off,wgpu_core=off,{}={}", env!("CARGO_CRATE_NAME"), log_level), level: log_level});
     app.add_state::<Multiplayer>();
+
+    #[cfg(target_arch = "wasm32")]
+    let network_config = {
+        let mut network_config = NetworkConfig::default();
+        if let Err(e) = network_config.apply_query(&query) {
+            warn!("Ignoring invalid network settings in URL: {}", e);
+        }
+        network_config
+    };
+    app.insert_resource(network_config);
+    app.insert_resource(world_config);
+    app.insert_resource(rate_limit_config);
+    #[cfg(not(target_arch = "wasm32"))]
+    app.insert_resource(bans);
     if headless {
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1. / server_args.tick_rate as f64))));
+        #[cfg(target_arch = "wasm32")]
         app.add_plugins(MinimalPlugins);
         app.insert_resource(State::new(Multiplayer::DedicatedServer));
     } else {
@@ -147,10 +184,19 @@ fn main() {
         EnemyPlugin {},
         ProjectilePlugin {},
         PositionPlugin {},
+        RateLimitPlugin {},
     ));
+    app.add_plugins(ConsolePlugin {is_headless: headless});
+    app.add_plugins(ShutdownPlugin {is_headless: headless});
     app.add_plugins(WebRtcServerPlugin {is_headless: headless});
+    #[cfg(target_arch = "wasm32")]
     app.add_plugins(WebRtcClientPlugin {is_headless: headless});
+    #[cfg(target_arch = "wasm32")]
     app.add_plugins(WebRtcBrowserPlugin {});
+    app.add_plugins(NetworkStatsPlugin {is_headless: headless});
+    app.add_plugins(NetworkSimulatorPlugin {is_headless: headless});
+
+    app.add_systems(OnEnter(Multiplayer::Undecided), teardown);
 
     // app.add_systems(Startup, setup_world.run_if(Multiplayer::state_is_authoritative()));
     app.add_systems(OnEnter(Multiplayer::DedicatedServer), setup_world);
@@ -160,6 +206,7 @@ fn main() {
     // app.add_systems(Startup, server_open_server.run_if(Multiplayer::state_is_server()));
     app.add_systems(OnEnter(Multiplayer::DedicatedServer), server_open_server);
     app.add_systems(OnEnter(Multiplayer::Server), server_open_server);
+    app.add_systems(Update, server_advertise_players.run_if(Multiplayer::state_is_server()));
 
     // app.add_systems(Startup, setup_client.run_if(Multiplayer::state_is_playable()));
     app.add_systems(OnEnter(Multiplayer::Server), setup_client);
@@ -170,38 +217,28 @@ fn main() {
     app.add_systems(Update, player_shoot.run_if(Multiplayer::state_is_playable()));
 
     // app.add_systems(Startup, client_open_browser.run_if(Multiplayer::state_is_client()));
+    #[cfg(target_arch = "wasm32")]
     app.add_systems(OnEnter(Multiplayer::Client), client_open_browser);
 
     app.run();
 }
 
-fn setup_world(mut commands: Commands) {
+fn setup_world(mut commands: Commands, config: Res<WorldConfig>) {
+    commands.insert_resource(WorldRng::new(config.seed));
     commands.spawn((
-        EnemySpawner {
-            image: "enemy.png".to_owned(),
-            timer: Timer::from_seconds(2., TimerMode::Repeating),
-        },
+        EnemySpawner::new("enemy.png", 2.),
         Position::from_translation(Vec3::new(300., 0., 0.5)),
     ));
     commands.spawn((
-        EnemySpawner {
-            image: "enemy.png".to_owned(),
-            timer: Timer::from_seconds(3., TimerMode::Repeating),
-        },
+        EnemySpawner::new("enemy.png", 3.),
         Position::from_translation(Vec3::new(-300., 400., 0.5)),
     ));
     commands.spawn((
-        EnemySpawner {
-            image: "enemy.png".to_owned(),
-            timer: Timer::from_seconds(4., TimerMode::Repeating),
-        },
+        EnemySpawner::new("enemy.png", 4.),
         Position::from_translation(Vec3::new(400., -300., 0.5)),
     ));
     commands.spawn((
-        EnemySpawner {
-            image: "enemy.png".to_owned(),
-            timer: Timer::from_seconds(5., TimerMode::Repeating),
-        },
+        EnemySpawner::new("enemy.png", 5.),
         Position::from_translation(Vec3::new(-600., -100., 0.5)),
     ));
 }
@@ -210,14 +247,45 @@ fn setup_client(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
 }
 
+// Back in the main menu, whatever the last game left behind goes
+fn teardown(world: &mut World) {
+    if let Some(server) = world.remove_non_send_resource::<WebRtcServer>() {
+        server.close();
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        if let Some(client) = world.remove_non_send_resource::<WebRtcClient>() {
+            client.close();
+        }
+        if let Some(browser) = world.remove_non_send_resource::<WebRtcBrowser>() {
+            browser.close();
+        }
+    }
+    let entities: Vec<Entity> = world.query_filtered::<Entity, Or<(With<Replication>, With<EnemySpawner>, With<Camera2d>)>>().iter(world).collect();
+    for entity in entities {
+        if let Some(entity) = world.get_entity_mut(entity) {
+            entity.despawn_recursive();
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
 fn client_open_browser(world: &mut World) {
     info!("Opening browser...");
-    world.insert_non_send_resource(WebRtcBrowser::new("wss://rose-signalling.webpubsub.azure.com/client/hubs/onlineservers".to_owned()));
+    let config = world.resource::<NetworkConfig>().clone();
+    world.insert_non_send_resource(WebRtcBrowser::new(config));
 }
 
 fn server_open_server(world: &mut World) {
     info!("Opening server...");
-    world.insert_non_send_resource(WebRtcServer::new("wss://rose-signalling.webpubsub.azure.com/client/hubs/onlineservers".to_owned(), "my-server".to_owned(), "some-game".to_owned()));
+    let config = world.resource::<NetworkConfig>().clone();
+    world.insert_non_send_resource(WebRtcServer::new(config));
+}
+
+fn server_advertise_players(server: Option<NonSend<WebRtcServer>>, players: Query<(), With<Player>>) {
+    if let Some(server) = server {
+        server.set_players(players.iter().count() as u32);
+    }
 }
 
 #[derive(Event, Serialize, Deserialize)]
@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts};
 
-use crate::{Multiplayer, PlayerInfo};
+use crate::{wasm_peers_rtc::config::{IceServer, NetworkConfig}, Multiplayer, PlayerInfo};
 
 pub struct MainMenuPlugin {}
 
@@ -19,13 +19,21 @@ impl Plugin for MainMenuPlugin {
 }
 
 impl MainMenuPlugin {
+    #[allow(clippy::too_many_arguments)]
     fn draw(
         mut contexts: EguiContexts,
         mut multiplayer_state: ResMut<NextState<Multiplayer>>,
         mut menu_state: ResMut<NextState<MainMenu>>,
         mut player_info: ResMut<PlayerInfo>,
+        mut network_config: ResMut<NetworkConfig>,
         mut username: Local<String>,
+        mut ice_servers: Local<Option<String>>,
+        mut password: Local<Option<String>>,
     ) {
+        let password = password.get_or_insert_with(|| network_config.password.clone().unwrap_or_default());
+        let ice_servers = ice_servers.get_or_insert_with(|| {
+            network_config.ice_servers.iter().map(|server| server.to_string()).collect::<Vec<_>>().join("\n")
+        });
         egui::CentralPanel::default().show(contexts.ctx_mut(), |ui| {
             ui.vertical_centered(|ui| {
                 ui.set_max_width(ui.available_width() * 0.4);
@@ -35,6 +43,36 @@ impl MainMenuPlugin {
                     columns[0].label("Username");
                     columns[1].text_edit_singleline(&mut *username);
                 });
+                egui::CollapsingHeader::new("Network settings").show(ui, |ui| {
+                    ui.columns(2, |columns| {
+                        columns[0].label("Signaling server");
+                        columns[1].text_edit_singleline(&mut network_config.signaling_url);
+                        columns[0].label("Game");
+                        columns[1].text_edit_singleline(&mut network_config.game_name);
+                        columns[0].label("Server name");
+                        columns[1].text_edit_singleline(&mut network_config.server_name);
+                        columns[0].label("Max players");
+                        columns[1].add(egui::DragValue::new(&mut network_config.max_players).clamp_range(1..=64));
+                        columns[0].label("Game mode");
+                        columns[1].text_edit_singleline(&mut network_config.mode);
+                        columns[0].label("Map");
+                        columns[1].text_edit_singleline(&mut network_config.map);
+                        columns[0].label("Password (empty for none)");
+                        if columns[1].add(egui::TextEdit::singleline(password).password(true)).changed() {
+                            network_config.password = Some(password.to_owned()).filter(|p| !p.is_empty());
+                        }
+                        columns[0].label("ICE servers (one per line)");
+                        if columns[1].text_edit_multiline(ice_servers).changed() {
+                            let parsed: Result<Vec<IceServer>, _> = ice_servers.lines()
+                                .filter(|line| !line.trim().is_empty())
+                                .map(|line| line.parse())
+                                .collect();
+                            if let Ok(parsed) = parsed {
+                                network_config.ice_servers = parsed;
+                            }
+                        }
+                    });
+                });
                 if ui.button("Singleplayer").clicked() {
                     multiplayer_state.set(Multiplayer::Singleplayer);
                     menu_state.set(MainMenu::InGame);
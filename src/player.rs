@@ -1,11 +1,23 @@
-use std::collections::HashMap;
+use std::{collections::{BTreeSet, HashMap}, fmt};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs, io, path::PathBuf};
 
 use bevy::{prelude::*, sprite::Anchor, text::Text2dBounds};
-use bevy_replicon::{replicon_core::replication_rules::{AppReplicationExt, Replication}, network_event::{client_event::{ClientEventAppExt, FromClient}, EventType, server_event::{ServerEventAppExt, ToClients, SendMode}}};
-use renet::{RenetClient, ClientId, ServerEvent};
+use bevy_egui::{egui, EguiContexts};
+use bevy_replicon::{replicon_core::replication_rules::{AppReplicationExt, Replication}, network_event::{client_event::{ClientEventAppExt, FromClient}, EventType, server_event::{ServerEventAppExt, ToClients, SendMode}}, server::SERVER_ID};
+use renet::{RenetClient, RenetServer, ClientId, ServerEvent};
 use serde::{Serialize, Deserialize};
 
-use crate::{player_controller::PlayerController, position::Position, Multiplayer, PlayerInfo};
+#[cfg(target_arch = "wasm32")]
+use crate::wasm_peers_rtc::client::LeaveServerEvent;
+use crate::{console::{AdminCommand, BanTarget}, player_controller::PlayerController, position::Position, rate_limit::{ClientEventKind, ClientRateLimits, RateLimiter}, wasm_peers_rtc::config::NetworkConfig, Multiplayer, PlayerInfo};
+
+// How long a disconnected player is kept around, waiting for its client to rejoin
+const RECONNECT_GRACE_PERIOD_SECS: f32 = 30.;
+// How long a rejected client gets to read why and leave, before the server hangs up on it
+const REJECTED_DISCONNECT_DELAY_SECS: f32 = 2.;
+// How long a connected client has to join, since it's sent the world from the moment it connects
+const JOIN_TIMEOUT_SECS: f32 = 10.;
 
 pub struct PlayerPlugin {}
 
@@ -15,16 +27,26 @@ impl Plugin for PlayerPlugin {
         app.replicate::<Score>();
         app.add_client_event::<PlayerJoinEvent>(EventType::Ordered);
         app.add_server_event::<PlayerSpawnEvent>(EventType::Ordered);
+        app.add_server_event::<PlayerRejectEvent>(EventType::Ordered);
         app.add_client_event::<PlayerMoveEvent>(EventType::Ordered);
 
         app.add_systems(Update, (
             handle_events_system.run_if(Multiplayer::state_is_server()),
-            (player_joined, player_moved).run_if(Multiplayer::state_is_authoritative())
+            (player_joined, player_moved, expire_disconnected).run_if(Multiplayer::state_is_authoritative()),
+            (list_players, kick_players, unban_players, list_bans, restart_round).run_if(Multiplayer::state_is_server()),
+            (disconnect_rejected, disconnect_unjoined).run_if(resource_exists::<RenetServer>())
         ));
         app.init_resource::<ClientPlayers>();
+        app.init_resource::<RejectedClients>();
+        app.init_resource::<JoiningClients>();
+        app.init_resource::<BanList>();
 
         app.add_systems(Update, (added_players, update, player_spawned, my_player).run_if(Multiplayer::state_is_playable()));
         app.add_systems(Update, join_server.run_if(Multiplayer::state_is_playable()));
+        app.add_systems(Update, player_rejected.run_if(Multiplayer::state_is_client()));
+        app.add_systems(Update, show_join_rejection.run_if(resource_exists::<JoinRejection>()));
+        app.add_systems(Update, show_admin_panel.run_if(in_state(Multiplayer::Server)));
+        app.add_systems(OnEnter(Multiplayer::Undecided), forget_clients);
         app.init_resource::<ResClientId>();
     }
 }
@@ -35,57 +57,511 @@ pub struct Player {
     username: String
 }
 
+pub type SessionToken = u64;
+
+// Server-side only, identifies the client that owns this player across reconnects
+#[derive(Component)]
+struct Session {
+    token: SessionToken
+}
+
+// Server-side only, the player's client is gone and has until the timer finishes to rejoin
+#[derive(Component)]
+struct Disconnected {
+    timer: Timer
+}
+
 #[derive(Event, Serialize, Deserialize, Debug)]
 struct PlayerJoinEvent {
-    username: String
+    username: String,
+    session: Option<SessionToken> // Set when rejoining after a disconnect
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+enum RejectReason {
+    ServerFull,
+    Kicked,
+    Banned
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::ServerFull => write!(f, "Server is full"),
+            RejectReason::Kicked => write!(f, "Kicked by the server"),
+            RejectReason::Banned => write!(f, "Banned from this server"),
+        }
+    }
+}
+
+// Sent instead of PlayerSpawnEvent, the server disconnects the client shortly after
+#[derive(Event, Serialize, Deserialize)]
+struct PlayerRejectEvent {
+    reason: RejectReason
+}
+
+// Client-side only, why the server turned us away
+#[derive(Resource)]
+struct JoinRejection {
+    reason: RejectReason
+}
+
+// Server-side only, clients waiting to be disconnected after being rejected
+#[derive(Resource, Default)]
+struct RejectedClients {
+    timers: HashMap<ClientId, Timer>
+}
+
+// Server-side only, connected clients that have yet to send a PlayerJoinEvent, disconnected once their timer finishes
+#[derive(Resource, Default)]
+struct JoiningClients {
+    timers: HashMap<ClientId, Timer>
+}
+
+// Server-side only, who `player_joined` refuses. Saved to `path` on every change, when there is one
+#[derive(Resource, Default, Serialize, Deserialize)]
+pub struct BanList {
+    usernames: BTreeSet<String>,
+    sessions: BTreeSet<SessionToken>,
+    #[serde(skip)]
+    #[cfg(not(target_arch = "wasm32"))]
+    path: Option<PathBuf>
+}
+
+impl BanList {
+    // A missing file is an empty ban list, it's created on the first ban
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: PathBuf) -> Result<BanList, String> {
+        let mut bans: BanList = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| format!("Invalid ban list `{}`: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BanList::default(),
+            Err(e) => return Err(format!("Can't read ban list `{}`: {}", path.display(), e)),
+        };
+        bans.path = Some(path);
+        Ok(bans)
+    }
+
+    fn is_banned(&self, username: &str, session: Option<SessionToken>) -> bool {
+        self.usernames.contains(username) || session.is_some_and(|session| self.sessions.contains(&session))
+    }
+
+    // Browsers have no file to keep bans in, there they last until the host leaves
+    fn save(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(path) = &self.path {
+            let contents = serde_json::to_string_pretty(self).expect("Ban list is always serializable");
+            if let Err(e) = fs::write(path, contents) {
+                warn!("Failed to save ban list `{}`: {}", path.display(), e);
+            }
+        }
+    }
 }
 
 #[derive(Event, Serialize, Deserialize)]
 struct PlayerSpawnEvent {
-    client_id: u32
+    client_id: u32,
+    session: SessionToken
 }
 
-fn join_server(multiplayer: Res<State<Multiplayer>>, client: Option<Res<RenetClient>>, mut connected: Local<bool>, mut writer: EventWriter<PlayerJoinEvent>, player_info: Res<PlayerInfo>) {
+fn join_server(
+    multiplayer: Res<State<Multiplayer>>,
+    client: Option<Res<RenetClient>>,
+    mut connected: Local<bool>,
+    mut writer: EventWriter<PlayerJoinEvent>,
+    player_info: Res<PlayerInfo>,
+    client_id: Res<ResClientId>
+) {
     let client_ready = *multiplayer == Multiplayer::Client && client.map_or(false, |c| c.is_connected());
     let authoritative_ready = multiplayer.is_authoritative();
+    if !client_ready && !authoritative_ready {
+        // Lost the server, join again once reconnected
+        *connected = false;
+    }
     if !*connected && (client_ready || authoritative_ready) {
         *connected = true;
         info!("Sending PlayerJoinEvent!");
-        writer.send(PlayerJoinEvent { username: player_info.username.to_owned() });
+        writer.send(PlayerJoinEvent { username: player_info.username.to_owned(), session: client_id.session });
     }
 }
 
-fn player_joined(mut commands: Commands, mut reader: EventReader<FromClient<PlayerJoinEvent>>, mut writer: EventWriter<ToClients<PlayerSpawnEvent>>, mut mapping: ResMut<ClientPlayers>) {
+#[allow(clippy::too_many_arguments)]
+fn player_joined(
+    mut commands: Commands,
+    mut reader: EventReader<FromClient<PlayerJoinEvent>>,
+    mut writer: EventWriter<ToClients<PlayerSpawnEvent>>,
+    mut reject_writer: EventWriter<ToClients<PlayerRejectEvent>>,
+    mut mapping: ResMut<ClientPlayers>,
+    mut rejected: ResMut<RejectedClients>,
+    mut joining: ResMut<JoiningClients>,
+    mut sessions: Query<(Entity, &Session, &mut Player, Has<Disconnected>)>,
+    mut server: Option<ResMut<RenetServer>>,
+    config: Res<NetworkConfig>,
+    bans: Res<BanList>
+) {
+    let mut players = sessions.iter().count() as u32;
     for evt in reader.read() {
-        info!("Received PlayerJoinEvent: {:?}", evt.event);
-        let username = evt.event.username.to_owned();
+        info!("Received PlayerJoinEvent: {:?}", evt.event.username);
         let client_id = evt.client_id.raw() as u32;
-        let entity = commands.spawn((
-            Player {client_id, username},
-            Score::default(),
-            Position::from_translation(Vec3::Z),
-            Replication
-        )).id();
+        joining.timers.remove(&evt.client_id);
+        let existing = sessions.iter_mut().find(|(_, session, _, _)| Some(session.token) == evt.event.session);
+
+        // The host's own player is always let in, and rejoining players keep their slot.
+        // The password was already checked by the transport, before the client got to see anything
+        let reject = if evt.client_id == SERVER_ID {
+            None
+        } else if bans.is_banned(&evt.event.username, evt.event.session) {
+            Some(RejectReason::Banned)
+        } else if existing.is_none() && players >= config.max_players {
+            Some(RejectReason::ServerFull)
+        } else {
+            None
+        };
+        if let Some(reason) = reject {
+            info!("Rejecting client {}: {}", evt.client_id, reason);
+            reject_writer.send(ToClients { mode: SendMode::Direct(evt.client_id), event: PlayerRejectEvent { reason } });
+            rejected.timers.insert(evt.client_id, Timer::from_seconds(REJECTED_DISCONNECT_DELAY_SECS, TimerMode::Once));
+            continue;
+        }
+
+        let (entity, session) = if let Some((entity, session, mut player, disconnected)) = existing {
+            // Rejoining: hand the old player over to the new client
+            info!("Client {} rejoined as {}", evt.client_id, player.username);
+            if let Some(old_client) = mapping.player_to_client.remove(&entity) {
+                mapping.client_to_player.remove(&old_client);
+                // Rejoined before we noticed the old connection drop, which would otherwise linger without a player
+                if !disconnected && old_client != evt.client_id {
+                    info!("Disconnecting client {}, taken over by client {}", old_client, evt.client_id);
+                    if let Some(server) = server.as_mut() {
+                        server.disconnect(old_client);
+                    }
+                }
+            }
+            player.client_id = client_id;
+            commands.entity(entity).remove::<Disconnected>();
+            (entity, session.token)
+        } else {
+            let session = rand::random();
+            let entity = commands.spawn((
+                Player {client_id, username: evt.event.username.to_owned()},
+                Score::default(),
+                Position::from_translation(Vec3::Z),
+                Session { token: session },
+                Replication
+            )).id();
+            players += 1;
+            (entity, session)
+        };
         mapping.client_to_player.insert(evt.client_id, entity);
         mapping.player_to_client.insert(entity, evt.client_id);
-        writer.send(ToClients { mode: SendMode::Direct(evt.client_id), event: PlayerSpawnEvent { client_id } });
+        writer.send(ToClients { mode: SendMode::Direct(evt.client_id), event: PlayerSpawnEvent { client_id, session } });
     }
 }
 
+fn disconnect_rejected(mut server: ResMut<RenetServer>, time: Res<Time>, mut rejected: ResMut<RejectedClients>) {
+    rejected.timers.retain(|client_id, timer| {
+        if timer.tick(time.delta()).finished() {
+            server.disconnect(*client_id);
+            return false;
+        }
+        true
+    });
+}
+
+// Clients that never join would be sent the world for as long as they stay connected
+fn disconnect_unjoined(mut server: ResMut<RenetServer>, time: Res<Time>, mut joining: ResMut<JoiningClients>) {
+    joining.timers.retain(|client_id, timer| {
+        if timer.tick(time.delta()).finished() {
+            warn!("Disconnecting client {}: didn't join within {}s", client_id, JOIN_TIMEOUT_SECS);
+            server.disconnect(*client_id);
+            return false;
+        }
+        true
+    });
+}
+
+fn player_rejected(
+    mut commands: Commands,
+    mut reader: EventReader<PlayerRejectEvent>,
+    #[cfg(target_arch = "wasm32")]
+    mut leave: EventWriter<LeaveServerEvent>
+) {
+    if let Some(evt) = reader.read().last() {
+        warn!("Server rejected us: {}", evt.reason);
+        commands.insert_resource(JoinRejection { reason: evt.reason });
+        #[cfg(target_arch = "wasm32")]
+        leave.send(LeaveServerEvent);
+    }
+}
+
+fn show_join_rejection(mut commands: Commands, mut contexts: EguiContexts, rejection: Res<JoinRejection>) {
+    let title = match rejection.reason {
+        RejectReason::Kicked => "Kicked from server",
+        _ => "Couldn't join server",
+    };
+    egui::Window::new(title).collapsible(false).resizable(false).show(contexts.ctx_mut(), |ui| {
+        ui.label(rejection.reason.to_string());
+        if ui.button("OK").clicked() {
+            commands.remove_resource::<JoinRejection>();
+        }
+    });
+}
+
+fn expire_disconnected(mut commands: Commands, time: Res<Time>, mut players: Query<(Entity, &mut Disconnected)>) {
+    for (entity, mut disconnected) in players.iter_mut() {
+        if disconnected.timer.tick(time.delta()).finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn list_players(
+    mut reader: EventReader<AdminCommand>,
+    mapping: Res<ClientPlayers>,
+    limits: Res<ClientRateLimits>,
+    players: Query<(Entity, &Player, &Score, Has<Disconnected>)>
+) {
+    for command in reader.read() {
+        if !matches!(command, AdminCommand::Players) {
+            continue;
+        }
+        let mut lines = vec![format!("{} players:", players.iter().count())];
+        for (entity, player, score, disconnected) in players.iter() {
+            let client_id = mapping.player_to_client.get(&entity);
+            let client = client_id.map_or("-".to_owned(), |client_id| client_id.to_string());
+            let dropped = client_id.map_or(0, |client_id| limits.dropped(*client_id));
+            let status = if disconnected { " (disconnected)" } else { "" };
+            lines.push(format!("  {:>20}  {}  score {}  {} events dropped{}", client, player.username, score.score, dropped, status));
+        }
+        info!("{}", lines.join("\n"));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn kick_players(
+    mut commands: Commands,
+    mut reader: EventReader<AdminCommand>,
+    mut mapping: ResMut<ClientPlayers>,
+    mut bans: ResMut<BanList>,
+    mut rejected: ResMut<RejectedClients>,
+    mut reject_writer: EventWriter<ToClients<PlayerRejectEvent>>,
+    server: Option<Res<RenetServer>>,
+    players: Query<(Entity, &Player, &Session)>
+) {
+    for command in reader.read() {
+        // Players to remove, whether or not their client is still around, and clients to turn away
+        let (targets, reason): (Vec<Entity>, _) = match command {
+            AdminCommand::Kick { client_id } => {
+                if !server.as_ref().is_some_and(|server| server.is_connected(*client_id)) {
+                    warn!("No client {} connected", client_id);
+                    continue;
+                }
+                if *client_id == SERVER_ID {
+                    warn!("Can't kick the host");
+                    continue;
+                }
+                // A client that hasn't joined yet has no player to remove
+                if let Some(player) = mapping.client_to_player.remove(client_id) {
+                    mapping.player_to_client.remove(&player);
+                    commands.entity(player).despawn_recursive();
+                }
+                reject(*client_id, RejectReason::Kicked, &mut reject_writer, &mut rejected);
+                continue;
+            }
+            AdminCommand::Ban { target: BanTarget::Username(username) } => {
+                info!("Banned {}", username);
+                bans.usernames.insert(username.to_owned());
+                bans.save();
+                (players.iter().filter(|(_, player, _)| &player.username == username).map(|(entity, _, _)| entity).collect(), RejectReason::Banned)
+            }
+            AdminCommand::Ban { target: BanTarget::Client(client_id) } => {
+                let Some(player) = mapping.client_to_player.get(client_id) else {
+                    warn!("Client {} has no player to ban", client_id);
+                    continue;
+                };
+                (vec![*player], RejectReason::Banned)
+            }
+            AdminCommand::Ban { target: BanTarget::Player(player) } => (vec![*player], RejectReason::Banned),
+            AdminCommand::Ban { target: BanTarget::PlayersNamed(username) } => {
+                let named: Vec<Entity> = players.iter().filter(|(_, player, _)| &player.username == username).map(|(entity, _, _)| entity).collect();
+                if named.is_empty() {
+                    warn!("No player called {}", username);
+                    continue;
+                }
+                (named, RejectReason::Banned)
+            }
+            _ => continue,
+        };
+        let by_session = !matches!(command, AdminCommand::Ban { target: BanTarget::Username(_) });
+        for entity in targets {
+            let Ok((_, player, session)) = players.get(entity) else {
+                warn!("No such player to ban");
+                continue;
+            };
+            if by_session {
+                info!("Banned session {} of {}", session.token, player.username);
+                bans.sessions.insert(session.token);
+                bans.save();
+            }
+            let client_id = mapping.player_to_client.get(&entity).copied();
+            if client_id == Some(SERVER_ID) {
+                warn!("Can't kick the host");
+                continue;
+            }
+            // Their player goes right away, instead of waiting for them to rejoin
+            mapping.player_to_client.remove(&entity);
+            commands.entity(entity).despawn_recursive();
+            // Players in their reconnect grace period have nobody left to tell
+            if let Some(client_id) = client_id {
+                mapping.client_to_player.remove(&client_id);
+                reject(client_id, reason, &mut reject_writer, &mut rejected);
+            }
+        }
+    }
+}
+
+// Tells the client why, then hangs up on it shortly after
+fn reject(client_id: ClientId, reason: RejectReason, writer: &mut EventWriter<ToClients<PlayerRejectEvent>>, rejected: &mut RejectedClients) {
+    info!("Kicking client {}: {}", client_id, reason);
+    writer.send(ToClients { mode: SendMode::Direct(client_id), event: PlayerRejectEvent { reason } });
+    rejected.timers.insert(client_id, Timer::from_seconds(REJECTED_DISCONNECT_DELAY_SECS, TimerMode::Once));
+}
+
+fn unban_players(mut reader: EventReader<AdminCommand>, mut bans: ResMut<BanList>) {
+    for command in reader.read() {
+        let (unbanned, banned) = match command {
+            AdminCommand::Unban { username } => (bans.usernames.remove(username), username.to_owned()),
+            AdminCommand::UnbanSession { session } => (bans.sessions.remove(session), format!("session {}", session)),
+            _ => continue,
+        };
+        if unbanned {
+            info!("Unbanned {}", banned);
+            bans.save();
+        } else {
+            warn!("{} isn't banned", banned);
+        }
+    }
+}
+
+fn list_bans(mut reader: EventReader<AdminCommand>, bans: Res<BanList>) {
+    for command in reader.read() {
+        if !matches!(command, AdminCommand::Bans) {
+            continue;
+        }
+        let mut lines = vec![format!("{} banned usernames, {} banned sessions:", bans.usernames.len(), bans.sessions.len())];
+        lines.extend(bans.usernames.iter().map(|username| format!("  {}", username)));
+        lines.extend(bans.sessions.iter().map(|session| format!("  session {}", session)));
+        info!("{}", lines.join("\n"));
+    }
+}
+
+// The host's view of `players` and `bans` in the console, toggled with F2
+#[allow(clippy::too_many_arguments)]
+fn show_admin_panel(
+    mut contexts: EguiContexts,
+    keys: Res<Input<KeyCode>>,
+    mut visible: Local<bool>,
+    mapping: Res<ClientPlayers>,
+    limits: Res<ClientRateLimits>,
+    bans: Res<BanList>,
+    players: Query<(Entity, &Player, &Score)>,
+    mut writer: EventWriter<AdminCommand>
+) {
+    if keys.just_pressed(KeyCode::F2) {
+        *visible = !*visible;
+    }
+    if !*visible {
+        return;
+    }
+    egui::Window::new("Players").resizable(false).show(contexts.ctx_mut(), |ui| {
+        egui::Grid::new("admin_players_grid").striped(true).show(ui, |ui| {
+            for (entity, player, score) in players.iter() {
+                ui.label(&player.username);
+                ui.label(score.score.to_string());
+                match mapping.player_to_client.get(&entity) {
+                    Some(client_id) if *client_id != SERVER_ID => {
+                        ui.label(format!("{} dropped", limits.dropped(*client_id))).on_hover_text("Events over the rate limits");
+                        if ui.button("Kick").clicked() {
+                            writer.send(AdminCommand::Kick { client_id: *client_id });
+                        }
+                        if ui.button("Ban").clicked() {
+                            writer.send(AdminCommand::Ban { target: BanTarget::Player(entity) });
+                        }
+                    }
+                    Some(_) => {
+                        ui.label("Host");
+                    }
+                    None => {
+                        ui.label("Disconnected");
+                        ui.label(""); // Nothing to kick
+                        // Keeps them from coming back within their grace period
+                        if ui.button("Ban").clicked() {
+                            writer.send(AdminCommand::Ban { target: BanTarget::Player(entity) });
+                        }
+                    }
+                }
+                ui.end_row();
+            }
+        });
+        if ui.button("Close server").clicked() {
+            writer.send(AdminCommand::Shutdown { reason: "The host closed the server".to_owned() });
+        }
+        if bans.usernames.is_empty() && bans.sessions.is_empty() {
+            return;
+        }
+        ui.separator();
+        ui.strong("Banned");
+        egui::Grid::new("admin_bans_grid").striped(true).show(ui, |ui| {
+            for username in bans.usernames.iter() {
+                ui.label(username);
+                if ui.button("Unban").clicked() {
+                    writer.send(AdminCommand::Unban { username: username.to_owned() });
+                }
+                ui.end_row();
+            }
+            for session in bans.sessions.iter() {
+                ui.label(format!("Session {}", session));
+                if ui.button("Unban").clicked() {
+                    writer.send(AdminCommand::UnbanSession { session: *session });
+                }
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn restart_round(mut reader: EventReader<AdminCommand>, mut players: Query<(&mut Score, &mut Position), With<Player>>) {
+    if !reader.read().any(|command| matches!(command, AdminCommand::RestartRound)) {
+        return;
+    }
+    for (mut score, mut position) in players.iter_mut() {
+        *score = Score::default();
+        position.translation = Vec3::Z;
+    }
+}
+
+// Client IDs start over with the next server
+fn forget_clients(mut mapping: ResMut<ClientPlayers>, mut rejected: ResMut<RejectedClients>, mut joining: ResMut<JoiningClients>) {
+    *mapping = ClientPlayers::default();
+    rejected.timers.clear();
+    joining.timers.clear();
+}
+
 #[derive(Resource)]
 struct ResClientId {
-    client_id: ClientId
+    client_id: ClientId,
+    session: Option<SessionToken>
 }
 
 impl Default for ResClientId {
     fn default() -> Self {
-        Self { client_id: ClientId::from_raw(1000000) }
+        Self { client_id: ClientId::from_raw(1000000), session: None }
     }
 }
 
 fn player_spawned(mut reader: EventReader<PlayerSpawnEvent>, mut client_id: ResMut<ResClientId>) {
     for evt in reader.read() {
         client_id.client_id = ClientId::from_raw(evt.client_id as u64);
+        client_id.session = Some(evt.session);
     }
 }
 
@@ -97,18 +573,26 @@ fn my_player(mut commands: Commands, players: Query<(Entity, &Player), Without<P
     }
 }
 
-fn handle_events_system(mut commands: Commands, mut mapping: ResMut<ClientPlayers>, mut server_events: EventReader<ServerEvent>) {
+fn handle_events_system(
+    mut commands: Commands,
+    mut mapping: ResMut<ClientPlayers>,
+    mut joining: ResMut<JoiningClients>,
+    mut server_events: EventReader<ServerEvent>
+) {
     for event in server_events.read() {
         match event {
             ServerEvent::ClientConnected { client_id } => {
                 println!("Client {client_id} connected");
+                joining.timers.insert(*client_id, Timer::from_seconds(JOIN_TIMEOUT_SECS, TimerMode::Once));
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
                 println!("Client {client_id} disconnected: {reason}");
+                joining.timers.remove(client_id);
                 let player = mapping.client_to_player.get(client_id).map(|e| *e);
                 if let Some(player) = player {
-                    if let Some(entity) = commands.get_entity(player) {
-                        entity.despawn_recursive();
+                    // Keep the player (and its score) around in case the client rejoins
+                    if let Some(mut entity) = commands.get_entity(player) {
+                        entity.insert(Disconnected { timer: Timer::from_seconds(RECONNECT_GRACE_PERIOD_SECS, TimerMode::Once) });
                     }
                     mapping.player_to_client.remove(&player);
                 }
@@ -176,8 +660,11 @@ pub struct PlayerMoveEvent {
     pub delta: Vec3
 }
 
-fn player_moved(mut reader: EventReader<FromClient<PlayerMoveEvent>>, mapping: Res<ClientPlayers>, mut players: Query<&mut Position, With<Player>>) {
+fn player_moved(mut reader: EventReader<FromClient<PlayerMoveEvent>>, mapping: Res<ClientPlayers>, mut players: Query<&mut Position, With<Player>>, mut limiter: RateLimiter) {
     for evt in reader.read() {
+        if !limiter.allow(evt.client_id, ClientEventKind::Move) {
+            continue;
+        }
         fn player_move(mapping: &ClientPlayers, client: ClientId, players: &mut Query<&mut Position, With<Player>>, delta: Vec3) -> Option<()> {
             let e = *mapping.client_to_player.get(&client)?;
             let mut position = players.get_mut(e).ok()?;
@@ -189,3 +676,140 @@ fn player_moved(mut reader: EventReader<FromClient<PlayerMoveEvent>>, mapping: R
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use renet::{ConnectionConfig, DisconnectReason};
+
+    use super::*;
+
+    const FIRST: ClientId = ClientId::from_raw(1);
+    const SECOND: ClientId = ClientId::from_raw(2);
+
+    fn server_world() -> World {
+        let mut world = World::new();
+        let mut server = RenetServer::new(ConnectionConfig::default());
+        server.add_connection(FIRST);
+        server.add_connection(SECOND);
+        world.insert_resource(server);
+        world.init_resource::<Events<FromClient<PlayerJoinEvent>>>();
+        world.init_resource::<Events<ToClients<PlayerSpawnEvent>>>();
+        world.init_resource::<Events<ToClients<PlayerRejectEvent>>>();
+        world.init_resource::<Events<ServerEvent>>();
+        world.init_resource::<Events<AdminCommand>>();
+        world.init_resource::<ClientPlayers>();
+        world.init_resource::<RejectedClients>();
+        world.init_resource::<JoiningClients>();
+        world.init_resource::<NetworkConfig>();
+        world.init_resource::<BanList>();
+        world
+    }
+
+    // Joins as `client_id`, returning the session the server handed out
+    fn join(world: &mut World, client_id: ClientId, username: &str, session: Option<SessionToken>) -> SessionToken {
+        world.send_event(FromClient { client_id, event: PlayerJoinEvent { username: username.to_owned(), session } });
+        world.run_system_once(player_joined);
+        // Each run reads with a fresh reader, which would see this event again
+        world.resource_mut::<Events<FromClient<PlayerJoinEvent>>>().clear();
+        let spawned = world.resource_mut::<Events<ToClients<PlayerSpawnEvent>>>().drain().last().expect("Client should have joined");
+        spawned.event.session
+    }
+
+    fn disconnect(world: &mut World, client_id: ClientId) {
+        world.resource_mut::<RenetServer>().remove_connection(client_id);
+        world.send_event(ServerEvent::ClientDisconnected { client_id, reason: DisconnectReason::Transport });
+        world.run_system_once(handle_events_system);
+        world.resource_mut::<Events<ServerEvent>>().clear();
+    }
+
+    fn ban(world: &mut World, target: BanTarget) -> Vec<ClientId> {
+        world.send_event(AdminCommand::Ban { target });
+        world.run_system_once(kick_players);
+        world.resource_mut::<Events<AdminCommand>>().clear();
+        world.resource_mut::<Events<ToClients<PlayerRejectEvent>>>().drain().map(|rejected| match rejected.mode {
+            SendMode::Direct(client_id) => client_id,
+            _ => panic!("Rejections should go to one client"),
+        }).collect()
+    }
+
+    fn player(world: &mut World) -> (Entity, u32, bool) {
+        let mut players = world.query::<(Entity, &Player, Has<Disconnected>)>();
+        let [(entity, player, disconnected)] = players.iter(world).collect::<Vec<_>>()[..] else {
+            panic!("Expected exactly one player");
+        };
+        (entity, player.client_id, disconnected)
+    }
+
+    #[test]
+    fn rejoining_after_a_disconnect_keeps_the_player() {
+        let mut world = server_world();
+        let session = join(&mut world, FIRST, "alice", None);
+        let (entity, _, _) = player(&mut world);
+
+        disconnect(&mut world, FIRST);
+        assert_eq!(player(&mut world), (entity, FIRST.raw() as u32, true));
+
+        assert_eq!(join(&mut world, SECOND, "alice", Some(session)), session);
+        assert_eq!(player(&mut world), (entity, SECOND.raw() as u32, false));
+        let mapping = world.resource::<ClientPlayers>();
+        assert_eq!(mapping.client_to_player.get(&SECOND), Some(&entity));
+        assert!(world.resource::<RenetServer>().is_connected(SECOND));
+    }
+
+    #[test]
+    fn rejoining_while_still_connected_disconnects_the_old_client() {
+        let mut world = server_world();
+        let session = join(&mut world, FIRST, "alice", None);
+        let (entity, _, _) = player(&mut world);
+
+        assert_eq!(join(&mut world, SECOND, "alice", Some(session)), session);
+        assert_eq!(player(&mut world), (entity, SECOND.raw() as u32, false));
+        let mapping = world.resource::<ClientPlayers>();
+        assert_eq!(mapping.client_to_player.get(&FIRST), None);
+        assert_eq!(mapping.player_to_client.get(&entity), Some(&SECOND));
+        let server = world.resource::<RenetServer>();
+        assert!(!server.is_connected(FIRST));
+        assert!(server.is_connected(SECOND));
+    }
+
+    #[test]
+    fn banning_a_username_removes_disconnected_players_too() {
+        let mut world = server_world();
+        join(&mut world, FIRST, "alice", None);
+        join(&mut world, SECOND, "alice", None);
+        disconnect(&mut world, FIRST);
+
+        assert_eq!(ban(&mut world, BanTarget::Username("alice".to_owned())), vec![SECOND]);
+        assert_eq!(world.query::<&Player>().iter(&world).count(), 0);
+        assert!(world.resource::<BanList>().is_banned("alice", None));
+    }
+
+    #[test]
+    fn banning_a_disconnected_player_bans_its_session() {
+        let mut world = server_world();
+        let session = join(&mut world, FIRST, "alice", None);
+        disconnect(&mut world, FIRST);
+        let (entity, _, _) = player(&mut world);
+
+        assert_eq!(ban(&mut world, BanTarget::Player(entity)), vec![]);
+        assert_eq!(world.query::<&Player>().iter(&world).count(), 0);
+        let bans = world.resource::<BanList>();
+        assert!(bans.is_banned("bob", Some(session)));
+        assert!(!bans.is_banned("alice", None));
+    }
+
+    #[test]
+    fn banning_by_name_bans_sessions_but_not_the_name() {
+        let mut world = server_world();
+        let first = join(&mut world, FIRST, "alice", None);
+        let second = join(&mut world, SECOND, "alice", None);
+        disconnect(&mut world, FIRST);
+
+        assert_eq!(ban(&mut world, BanTarget::PlayersNamed("alice".to_owned())), vec![SECOND]);
+        let bans = world.resource::<BanList>();
+        assert!(bans.is_banned("bob", Some(first)));
+        assert!(bans.is_banned("bob", Some(second)));
+        assert!(!bans.is_banned("alice", None));
+    }
+}
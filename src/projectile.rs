@@ -2,7 +2,7 @@ use bevy::{prelude::*, sprite::Anchor, text::{Text2dBounds, TextLayoutInfo}};
 use bevy_replicon::{network_event::{EventType, client_event::{ClientEventAppExt, FromClient}}, replicon_core::replication_rules::{Replication, AppReplicationExt}};
 use serde::{Deserialize, Serialize};
 
-use crate::{enemy::Enemy, player::{Player, Score}, position::Position, Multiplayer, PlayerShootEvent};
+use crate::{console::AdminCommand, enemy::Enemy, player::{Player, Score}, position::Position, rate_limit::{ClientEventKind, RateLimiter}, Multiplayer, PlayerShootEvent};
 
 pub struct ProjectilePlugin {}
 
@@ -12,6 +12,7 @@ impl Plugin for ProjectilePlugin {
         app.replicate::<Projectile>();
 
         app.add_systems(Update, (player_shoot, step, collide).run_if(Multiplayer::state_is_authoritative()));
+        app.add_systems(Update, restart_round.run_if(Multiplayer::state_is_server()));
 
         app.add_systems(Update, added_projectile.run_if(Multiplayer::state_is_playable()));
     }
@@ -46,13 +47,25 @@ impl Default for Projectile {
     }
 }
 
-fn player_shoot(mut commands: Commands, mut reader: EventReader<FromClient<PlayerShootEvent>>) {
+fn player_shoot(mut commands: Commands, mut reader: EventReader<FromClient<PlayerShootEvent>>, mut limiter: RateLimiter) {
     for evt in reader.read() {
+        if !limiter.allow(evt.client_id, ClientEventKind::Shoot) {
+            continue;
+        }
         let projectile = evt.event.projectile.clone();
         commands.spawn((projectile, Position::from_translation(evt.event.projectile.initial_position.clone() + Vec3::Z * 3.), Replication));
     }
 }
 
+fn restart_round(mut commands: Commands, mut reader: EventReader<AdminCommand>, projectiles: Query<Entity, With<Projectile>>) {
+    if !reader.read().any(|command| matches!(command, AdminCommand::RestartRound)) {
+        return;
+    }
+    for projectile in projectiles.iter() {
+        commands.entity(projectile).despawn_recursive();
+    }
+}
+
 fn added_projectile(
     mut commands: Commands,
     added: Query<(Entity, &Projectile), Added<Projectile>>,
@@ -0,0 +1,254 @@
+use std::{collections::HashMap, fmt};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_replicon::server::SERVER_ID;
+use clap::Args;
+use renet::{ClientId, ServerEvent};
+
+use crate::{console::AdminCommand, Multiplayer};
+
+pub const DEFAULT_MOVE_RATE: u32 = 240;
+pub const DEFAULT_SHOOT_RATE: u32 = 20;
+
+pub struct RateLimitPlugin {}
+
+impl Plugin for RateLimitPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClientRateLimits>();
+        app.add_systems(Update, (review_drops, forget_disconnected).run_if(Multiplayer::state_is_server()));
+        app.add_systems(OnEnter(Multiplayer::Undecided), forget_clients);
+    }
+}
+
+#[derive(Resource, Args, Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Most movement events a client may send per second, 0 for no limit
+    #[arg(long = "move-rate", value_name = "EVENTS/S", default_value_t = DEFAULT_MOVE_RATE)]
+    pub move_rate: u32,
+    /// Most shots a client may fire per second, 0 for no limit
+    #[arg(long = "shoot-rate", value_name = "EVENTS/S", default_value_t = DEFAULT_SHOOT_RATE)]
+    pub shoot_rate: u32,
+    /// Kick clients that get more than this many events dropped within a second. Never kicks by default
+    #[arg(long = "rate-limit-kick", value_name = "DROPPED")]
+    pub kick_after: Option<u32>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            move_rate: DEFAULT_MOVE_RATE,
+            shoot_rate: DEFAULT_SHOOT_RATE,
+            kick_after: None,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn rate(&self, kind: ClientEventKind) -> u32 {
+        match kind {
+            ClientEventKind::Move => self.move_rate,
+            ClientEventKind::Shoot => self.shoot_rate,
+        }
+    }
+}
+
+// Client events that are rate limited, each with its own budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientEventKind {
+    Move,
+    Shoot
+}
+
+impl fmt::Display for ClientEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientEventKind::Move => write!(f, "move"),
+            ClientEventKind::Shoot => write!(f, "shoot"),
+        }
+    }
+}
+
+// Server-side only, each connected client's budgets and what it got dropped
+#[derive(Resource, Default)]
+pub struct ClientRateLimits {
+    clients: HashMap<ClientId, ClientLimits>
+}
+
+impl ClientRateLimits {
+    // Events dropped since the client connected
+    pub fn dropped(&self, client_id: ClientId) -> u64 {
+        self.clients.get(&client_id).map_or(0, |limits| limits.dropped)
+    }
+}
+
+#[derive(Default)]
+struct ClientLimits {
+    buckets: HashMap<ClientEventKind, TokenBucket>,
+    dropped: u64,
+    recently_dropped: HashMap<ClientEventKind, u32>, // Since `review_drops` last looked
+    kicked: bool // Nothing more from it is handled while it's being disconnected
+}
+
+// Refills at `rate` tokens per second and holds up to a second's worth, so short bursts pass
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: f64
+}
+
+impl TokenBucket {
+    fn take(&mut self, rate: u32, now: f64) -> bool {
+        self.tokens = (self.tokens + (now - self.refilled_at) * rate as f64).min(rate as f64);
+        self.refilled_at = now;
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
+
+// Decides which client events get handled, for the systems handling them
+#[derive(SystemParam)]
+pub struct RateLimiter<'w> {
+    limits: ResMut<'w, ClientRateLimits>,
+    config: Res<'w, RateLimitConfig>,
+    time: Res<'w, Time>
+}
+
+impl RateLimiter<'_> {
+    // Whether an event from this client fits its budget. Those that don't are counted and should be dropped
+    pub fn allow(&mut self, client_id: ClientId, kind: ClientEventKind) -> bool {
+        let rate = self.config.rate(kind);
+        // The host's own events don't go over the network
+        if client_id == SERVER_ID || rate == 0 {
+            return true;
+        }
+        let now = self.time.elapsed_seconds_f64();
+        let limits = self.limits.clients.entry(client_id).or_default();
+        if limits.kicked {
+            return false;
+        }
+        let bucket = limits.buckets.entry(kind).or_insert(TokenBucket { tokens: rate as f64, refilled_at: now });
+        if bucket.take(rate, now) {
+            return true;
+        }
+        limits.dropped += 1;
+        *limits.recently_dropped.entry(kind).or_default() += 1;
+        false
+    }
+}
+
+// Once a second, reports who got events dropped and kicks the worst offenders
+fn review_drops(
+    mut limits: ResMut<ClientRateLimits>,
+    config: Res<RateLimitConfig>,
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+    mut writer: EventWriter<AdminCommand>
+) {
+    let timer = timer.get_or_insert_with(|| Timer::from_seconds(1., TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (client_id, client) in limits.clients.iter_mut() {
+        let recently_dropped: u32 = client.recently_dropped.values().sum();
+        if recently_dropped == 0 {
+            continue;
+        }
+        let kinds: Vec<String> = client.recently_dropped.drain().map(|(kind, dropped)| format!("{} {}", dropped, kind)).collect();
+        warn!("Client {} is over its rate limits, dropped {} events", client_id, kinds.join(", "));
+        if config.kick_after.is_some_and(|kick_after| recently_dropped > kick_after) {
+            warn!("Kicking client {} for flooding", client_id);
+            client.kicked = true;
+            writer.send(AdminCommand::Kick { client_id: *client_id });
+        }
+    }
+}
+
+fn forget_clients(mut limits: ResMut<ClientRateLimits>) {
+    limits.clients.clear();
+}
+
+fn forget_disconnected(mut limits: ResMut<ClientRateLimits>, mut server_events: EventReader<ServerEvent>) {
+    for event in server_events.read() {
+        if let ServerEvent::ClientDisconnected { client_id, .. } = event {
+            limits.clients.remove(client_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    const CLIENT: ClientId = ClientId::from_raw(1);
+
+    fn taken(bucket: &mut TokenBucket, rate: u32, now: f64, tries: u32) -> usize {
+        (0..tries).filter(|_| bucket.take(rate, now)).count()
+    }
+
+    #[test]
+    fn bucket_refills_at_the_rate() {
+        let mut bucket = TokenBucket { tokens: 8., refilled_at: 0. };
+        assert_eq!(taken(&mut bucket, 8, 0., 20), 8);
+        assert_eq!(taken(&mut bucket, 8, 0.5, 20), 4);
+        assert_eq!(taken(&mut bucket, 8, 0.75, 20), 2);
+    }
+
+    #[test]
+    fn bucket_holds_at_most_a_seconds_worth() {
+        let mut bucket = TokenBucket { tokens: 10., refilled_at: 0. };
+        assert_eq!(taken(&mut bucket, 10, 0., 20), 10);
+        assert_eq!(taken(&mut bucket, 10, 60., 100), 10);
+    }
+
+    fn world(config: RateLimitConfig) -> World {
+        let mut world = World::new();
+        world.insert_resource(config);
+        world.init_resource::<ClientRateLimits>();
+        world.init_resource::<Time>();
+        world.init_resource::<Events<AdminCommand>>();
+        world
+    }
+
+    fn allowed(world: &mut World, client_id: ClientId, kind: ClientEventKind, tries: u32) -> usize {
+        world.run_system_once(move |mut limiter: RateLimiter| (0..tries).filter(|_| limiter.allow(client_id, kind)).count())
+    }
+
+    #[test]
+    fn limits_each_kind_separately_and_zero_means_no_limit() {
+        let mut world = world(RateLimitConfig { move_rate: 0, shoot_rate: 5, kick_after: None });
+        assert_eq!(allowed(&mut world, CLIENT, ClientEventKind::Move, 1000), 1000);
+        assert_eq!(allowed(&mut world, CLIENT, ClientEventKind::Shoot, 10), 5);
+        assert_eq!(allowed(&mut world, SERVER_ID, ClientEventKind::Shoot, 10), 10);
+        assert_eq!(world.resource::<ClientRateLimits>().dropped(CLIENT), 5);
+    }
+
+    // Drops `dropped` events within the same second, then lets review_drops look at them
+    fn review(kick_after: Option<u32>, dropped: u32) -> (World, Vec<ClientId>) {
+        let mut world = world(RateLimitConfig { move_rate: 1, shoot_rate: 1, kick_after });
+        allowed(&mut world, CLIENT, ClientEventKind::Move, dropped + 1);
+        world.resource_mut::<Time>().advance_by(Duration::from_secs(1));
+        world.run_system_once(review_drops);
+        let kicked = world.resource_mut::<Events<AdminCommand>>().drain().filter_map(|command| match command {
+            AdminCommand::Kick { client_id } => Some(client_id),
+            _ => None,
+        }).collect();
+        (world, kicked)
+    }
+
+    #[test]
+    fn kicks_only_past_the_threshold() {
+        assert_eq!(review(None, 100).1, vec![]);
+        assert_eq!(review(Some(5), 5).1, vec![]);
+        let (mut world, kicked) = review(Some(5), 6);
+        assert_eq!(kicked, vec![CLIENT]);
+        // Nothing more from a kicked client is let through, even with a full bucket
+        world.resource_mut::<Time>().advance_by(Duration::from_secs(10));
+        assert_eq!(allowed(&mut world, CLIENT, ClientEventKind::Shoot, 1), 0);
+    }
+}
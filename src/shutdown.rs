@@ -0,0 +1,182 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
+use bevy::{app::AppExit, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use bevy_replicon::network_event::{EventType, server_event::{ServerEventAppExt, ToClients, SendMode}};
+use renet::RenetServer;
+use serde::{Serialize, Deserialize};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{console::DEFAULT_SHUTDOWN_REASON, wasm_peers_rtc::util::spawn};
+use crate::{console::AdminCommand, main_menu::MainMenu, wasm_peers_rtc::server::WebRtcServer, Multiplayer};
+
+// How long clients get to receive ServerClosingEvent and leave on their own
+const DRAIN_SECS: f32 = 1.;
+// How long renet gets to tell the remaining clients they're disconnected
+const DISCONNECT_SECS: f32 = 0.5;
+
+pub struct ShutdownPlugin {
+    pub is_headless: bool
+}
+
+impl Plugin for ShutdownPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_server_event::<ServerClosingEvent>(EventType::Ordered);
+
+        app.add_systems(Update, begin_shutdown.run_if(Multiplayer::state_is_server()));
+        app.add_systems(Update, finish_shutdown.run_if(resource_exists::<ShuttingDown>()));
+        app.add_systems(Update, server_closing.run_if(Multiplayer::state_is_client()));
+        if !self.is_headless {
+            app.add_systems(Update, show_server_closed.run_if(resource_exists::<ServerClosed>()));
+        }
+
+        // Ctrl+C shuts down like the console's `shutdown` would
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.is_headless {
+            app.insert_resource(CtrlC::listen());
+            app.add_systems(Update, watch_ctrl_c.run_if(Multiplayer::state_is_server()));
+        }
+    }
+}
+
+// Tells clients to go back to the main menu, and why
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct ServerClosingEvent {
+    pub reason: String
+}
+
+// Server-side only, counting down to disconnecting everyone and then to actually stopping
+#[derive(Resource)]
+struct ShuttingDown {
+    timer: Timer,
+    disconnected: bool
+}
+
+// Client-side only, why the server we were on went away
+#[derive(Resource)]
+struct ServerClosed {
+    reason: String
+}
+
+fn begin_shutdown(
+    mut commands: Commands,
+    mut reader: EventReader<AdminCommand>,
+    mut writer: EventWriter<ToClients<ServerClosingEvent>>,
+    rtc_server: Option<NonSend<WebRtcServer>>,
+    shutting_down: Option<Res<ShuttingDown>>
+) {
+    let reason = reader.read().find_map(|command| match command {
+        AdminCommand::Shutdown { reason } => Some(reason.to_owned()),
+        _ => None,
+    });
+    let Some(reason) = reason else {
+        return;
+    };
+    if shutting_down.is_some() {
+        warn!("Already shutting down");
+        return;
+    }
+    info!("Shutting down: {}", reason);
+    writer.send(ToClients { mode: SendMode::Broadcast, event: ServerClosingEvent { reason } });
+    // Nobody should find us in the server list anymore while clients are leaving
+    if let Some(rtc_server) = rtc_server {
+        rtc_server.unregister();
+    }
+    commands.insert_resource(ShuttingDown { timer: Timer::from_seconds(DRAIN_SECS, TimerMode::Once), disconnected: false });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn finish_shutdown(
+    mut commands: Commands,
+    mut shutting_down: ResMut<ShuttingDown>,
+    time: Res<Time>,
+    renet_server: Option<ResMut<RenetServer>>,
+    rtc_server: Option<NonSend<WebRtcServer>>,
+    multiplayer: Res<State<Multiplayer>>,
+    mut next_multiplayer: ResMut<NextState<Multiplayer>>,
+    next_menu: Option<ResMut<NextState<MainMenu>>>,
+    mut exit: EventWriter<AppExit>
+) {
+    if !shutting_down.timer.tick(time.delta()).finished() {
+        return;
+    }
+    if !shutting_down.disconnected {
+        // Whoever didn't leave by now gets disconnected by renet
+        if let Some(mut renet_server) = renet_server {
+            renet_server.disconnect_all();
+        }
+        shutting_down.disconnected = true;
+        shutting_down.timer = Timer::from_seconds(DISCONNECT_SECS, TimerMode::Once);
+        return;
+    }
+    commands.remove_resource::<ShuttingDown>();
+    if *multiplayer == Multiplayer::DedicatedServer {
+        if let Some(rtc_server) = rtc_server {
+            rtc_server.close();
+        }
+        info!("Shut down");
+        exit.send(AppExit);
+    } else {
+        // A host goes back to the main menu, like its clients did
+        next_multiplayer.set(Multiplayer::Undecided);
+        if let Some(mut next_menu) = next_menu {
+            next_menu.set(MainMenu::MainMenu);
+        }
+    }
+}
+
+fn server_closing(
+    mut commands: Commands,
+    mut reader: EventReader<ServerClosingEvent>,
+    mut next_multiplayer: ResMut<NextState<Multiplayer>>,
+    next_menu: Option<ResMut<NextState<MainMenu>>>
+) {
+    let Some(evt) = reader.read().last() else {
+        return;
+    };
+    info!("Server is closing: {}", evt.reason);
+    commands.insert_resource(ServerClosed { reason: evt.reason.to_owned() });
+    next_multiplayer.set(Multiplayer::Undecided);
+    if let Some(mut next_menu) = next_menu {
+        next_menu.set(MainMenu::MainMenu);
+    }
+}
+
+fn show_server_closed(mut commands: Commands, mut contexts: EguiContexts, closed: Res<ServerClosed>) {
+    egui::Window::new("Server closed").collapsible(false).resizable(false).show(contexts.ctx_mut(), |ui| {
+        ui.label(&closed.reason);
+        if ui.button("OK").clicked() {
+            commands.remove_resource::<ServerClosed>();
+        }
+    });
+}
+
+// Set once Ctrl+C was pressed. A second Ctrl+C exits right away, in case shutting down hangs
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Resource)]
+struct CtrlC(Arc<AtomicBool>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CtrlC {
+    fn listen() -> CtrlC {
+        let pressed = Arc::new(AtomicBool::new(false));
+        let pressed_clone = pressed.clone();
+        spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if pressed_clone.swap(true, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+            }
+        });
+        CtrlC(pressed)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn watch_ctrl_c(ctrl_c: Res<CtrlC>, mut writer: EventWriter<AdminCommand>, mut sent: Local<bool>) {
+    if !*sent && ctrl_c.0.load(Ordering::Relaxed) {
+        *sent = true;
+        writer.send(AdminCommand::Shutdown { reason: DEFAULT_SHUTDOWN_REASON.to_owned() });
+    }
+}
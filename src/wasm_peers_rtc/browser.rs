@@ -1,85 +1,230 @@
-use std::collections::HashMap;
-
 use bevy::{ecs::system::SystemState, prelude::*};
 use bevy_egui::{egui, EguiContexts};
 
 use crate::wasm_peers_rtc::client::WebRtcBrowser;
 
-use super::{client::{WebRtcBrowserState, WebRtcClientState}, signaling::ServerEntry};
+use super::{client::WebRtcBrowserState, config::{NetworkConfig, PROTOCOL_VERSION}, signaling::ServerEntry, util::Backoff};
 
-pub struct WebRtcBrowserPlugin {}
+// How often to refresh the list while servers are being shown
+const REFRESH_INTERVAL_SECS: f32 = 10.;
+// How long the hub gets to answer a refresh before we assume it doesn't know how
+const REFRESH_TIMEOUT_SECS: f64 = 5.;
 
-#[derive(Resource)]
-struct Servers {
-    servers: HashMap<String, ServerEntry>
-}
+pub struct WebRtcBrowserPlugin {}
 
 #[derive(Event)]
 struct ConnectEvent {
     conn: String
 }
 
-impl Plugin for WebRtcBrowserPlugin {
-    fn build(&self, app: &mut App) {
-        app.add_event::<ConnectEvent>();
-        app.add_systems(OnEnter(WebRtcBrowserState::Connected), Self::setup);
-        app.add_systems(Update, Self::browser_get_servers.run_if(in_state(WebRtcBrowserState::Connected)));
-        app.add_systems(Update, Self::browser_show_servers.run_if(resource_exists::<Servers>()));
-        app.add_systems(OnEnter(WebRtcClientState::Connected), Self::teardown);
-    }
+#[derive(Event)]
+struct RefreshEvent;
+
+#[derive(Default, Clone, Copy, PartialEq)]
+enum SortColumn {
+    #[default]
+    Name,
+    Mode,
+    Map,
+    Players,
+    Version
 }
 
-impl WebRtcBrowserPlugin {
-    fn setup() {
+impl SortColumn {
+    const ALL: [(SortColumn, &'static str); 5] = [
+        (SortColumn::Name, "Name"),
+        (SortColumn::Mode, "Mode"),
+        (SortColumn::Map, "Map"),
+        (SortColumn::Players, "Players"),
+        (SortColumn::Version, "Version"),
+    ];
 
+    fn compare(&self, a: &ServerEntry, b: &ServerEntry) -> std::cmp::Ordering {
+        match self {
+            SortColumn::Name => a.name.cmp(&b.name),
+            SortColumn::Mode => a.mode.cmp(&b.mode),
+            SortColumn::Map => a.map.cmp(&b.map),
+            SortColumn::Players => a.players.cmp(&b.players).then(a.max_players.cmp(&b.max_players)),
+            SortColumn::Version => a.version.cmp(&b.version),
+        }
     }
+}
+
+#[derive(Default)]
+struct ServerSort {
+    column: SortColumn,
+    descending: bool
+}
 
-    fn teardown(world: &mut World) {
-        world.remove_resource::<Servers>();
+#[derive(Resource, Default)]
+struct BrowserRefresh {
+    backoff: Backoff,
+    timer: Timer,
+    awaiting: Option<(u32, f64)>, // Lists received and time when the last refresh was sent, until one more arrives
+    reconnect_instead: bool // The hub never answered a refresh, but sends a list to every new connection
+}
+
+impl Plugin for WebRtcBrowserPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ConnectEvent>();
+        app.add_event::<RefreshEvent>();
+        app.init_resource::<BrowserRefresh>();
+        app.add_systems(Update, Self::browser_refresh.run_if(not(in_state(WebRtcBrowserState::Disconnected))));
+        app.add_systems(Update, Self::browser_connect.run_if(in_state(WebRtcBrowserState::Connected)));
+        app.add_systems(Update, Self::browser_show_servers.run_if(in_state(WebRtcBrowserState::Connected)));
     }
+}
 
-    fn browser_get_servers(world: &mut World) {
-        if world.contains_resource::<Servers>() {
-            let mut events = SystemState::<EventReader<ConnectEvent>>::new(world);
-            let mut reader = events.get_mut(world);
-            if let Some(e) = reader.read().last() {
-                let server_id = e.conn.to_owned();
-                let browser = world.remove_non_send_resource::<WebRtcBrowser>().unwrap();
-                let servers = browser.servers().unwrap();
-                let entry = servers.get(&server_id).unwrap();
-                info!("Client: connecting to server {:?} @ {}", entry, server_id);
-                let client = browser.connect(server_id);
-                world.insert_non_send_resource(client);
+impl WebRtcBrowserPlugin {
+    fn browser_refresh(
+        mut browser: NonSendMut<WebRtcBrowser>,
+        config: Res<NetworkConfig>,
+        time: Res<Time>,
+        mut refresh: ResMut<BrowserRefresh>,
+        mut events: EventReader<RefreshEvent>
+    ) {
+        refresh.timer.tick(time.delta());
+        if let Some((lists, sent_at)) = refresh.awaiting {
+            if browser.lists_received() != lists {
+                refresh.awaiting = None;
+            } else if time.elapsed_seconds_f64() - sent_at > REFRESH_TIMEOUT_SECS {
+                info!("Signaling server doesn't answer refreshes, reconnecting browser instead...");
+                refresh.awaiting = None;
+                refresh.reconnect_instead = true;
+                browser.close();
+                *browser = WebRtcBrowser::new(config.clone());
             }
-            return;
         }
-        let mut browser = world.non_send_resource_mut::<WebRtcBrowser>();
-        let servers = browser.servers().unwrap();
-        if servers.len() > 0 {
-            world.insert_resource(Servers { servers });
+        let requested = events.read().count() > 0;
+        if !requested && !refresh.timer.finished() {
             return;
         }
-        info!("No servers online yet...");
-        *browser = WebRtcBrowser::new("wss://rose-signalling.webpubsub.azure.com/client/hubs/onlineservers".to_owned()); // TODO proper refresh() with delay
+        if browser.is_closed() {
+            info!("Lost signaling server, reconnecting browser...");
+            *browser = WebRtcBrowser::new(config.clone());
+        } else if refresh.reconnect_instead {
+            // Still connecting from last time otherwise, the list comes once it's done
+            if browser.servers().is_some() {
+                browser.close();
+                *browser = WebRtcBrowser::new(config.clone());
+            }
+        } else if refresh.awaiting.is_none() {
+            browser.refresh();
+            refresh.awaiting = Some((browser.lists_received(), time.elapsed_seconds_f64()));
+        }
+        // Poll with backoff until servers show up, then settle into a steady interval
+        let delay = match browser.servers() {
+            Some(servers) if !servers.is_empty() => {
+                refresh.backoff = Backoff::default();
+                REFRESH_INTERVAL_SECS
+            }
+            _ => refresh.backoff.next_delay_ms() as f32 / 1000.,
+        };
+        refresh.timer = Timer::from_seconds(delay, TimerMode::Once);
     }
 
-    fn browser_show_servers(servers: Res<Servers>, mut contexts: EguiContexts, mut writer: EventWriter<ConnectEvent>) {
+    fn browser_connect(world: &mut World) {
+        let mut events = SystemState::<EventReader<ConnectEvent>>::new(world);
+        let mut reader = events.get_mut(world);
+        if let Some(e) = reader.read().last() {
+            let server_id = e.conn.to_owned();
+            let servers = world.non_send_resource::<WebRtcBrowser>().servers().unwrap();
+            let Some(entry) = servers.get(&server_id) else {
+                warn!("Client: server {} went offline", server_id);
+                return;
+            };
+            info!("Client: connecting to server {:?} @ {}", entry, server_id);
+            let password = world.resource::<NetworkConfig>().password.clone();
+            let browser = world.remove_non_send_resource::<WebRtcBrowser>().unwrap();
+            let client = browser.connect(server_id, password);
+            world.insert_non_send_resource(client);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn browser_show_servers(
+        browser: NonSend<WebRtcBrowser>,
+        refresh: Res<BrowserRefresh>,
+        mut contexts: EguiContexts,
+        mut connect: EventWriter<ConnectEvent>,
+        mut refresh_events: EventWriter<RefreshEvent>,
+        mut sort: Local<ServerSort>,
+        mut config: ResMut<NetworkConfig>,
+        mut password_prompt: Local<Option<(String, String)>> // Server and password typed so far
+    ) {
+        let mut servers: Vec<_> = browser.servers().unwrap_or_default().into_iter().collect();
+        servers.sort_by(|(a_conn, a), (b_conn, b)| sort.column.compare(a, b).then(a.name.cmp(&b.name)).then(a_conn.cmp(b_conn)));
+        if sort.descending {
+            servers.reverse();
+        }
         contexts.ctx_mut().set_visuals(egui::Visuals {
             window_rounding: 0.0.into(),
             ..default()
         });
         egui::Window::new("Servers").show(contexts.ctx_mut(), |ui| {
-            egui::Grid::new("serverlist").show(ui, |ui| {
-                for (conn, server) in servers.servers.iter() {
-                    ui.label(&server.game);
+            ui.horizontal(|ui| {
+                if ui.button("Refresh").clicked() {
+                    refresh_events.send(RefreshEvent);
+                }
+                ui.label(format!("Next refresh in {:.0}s", refresh.timer.remaining_secs().ceil()));
+            });
+            if servers.is_empty() {
+                ui.label("No servers online yet...");
+            }
+            egui::Grid::new("serverlist").striped(true).show(ui, |ui| {
+                for (column, header) in SortColumn::ALL {
+                    let selected = sort.column == column;
+                    let arrow = match (selected, sort.descending) {
+                        (false, _) => "",
+                        (true, false) => " ▲",
+                        (true, true) => " ▼",
+                    };
+                    if ui.selectable_label(selected, format!("{}{}", header, arrow)).clicked() {
+                        sort.descending = selected && !sort.descending;
+                        sort.column = column;
+                    }
+                }
+                ui.end_row();
+                for (conn, server) in servers.iter() {
                     ui.label(&server.name);
-                    ui.label(conn);
-                    if ui.button("Connect").clicked() {
-                        writer.send(ConnectEvent { conn: conn.to_owned() })
+                    ui.label(&server.mode);
+                    ui.label(&server.map);
+                    ui.label(format!("{}/{}", server.players, server.max_players));
+                    if server.version == PROTOCOL_VERSION {
+                        ui.label(server.version.to_string());
+                    } else {
+                        ui.colored_label(egui::Color32::RED, server.version.to_string());
+                    }
+                    ui.label(if server.password { "🔒" } else { "" });
+                    let compatible = server.version == PROTOCOL_VERSION;
+                    if ui.add_enabled(compatible, egui::Button::new("Connect")).on_disabled_hover_text("Incompatible version").clicked() {
+                        if server.password {
+                            *password_prompt = Some((conn.to_owned(), config.password.clone().unwrap_or_default()));
+                        } else {
+                            connect.send(ConnectEvent { conn: conn.to_owned() })
+                        }
                     }
                     ui.end_row();
                 }
             });
         });
+        if let Some((conn, password)) = password_prompt.as_mut() {
+            let (mut join, mut cancel) = (false, false);
+            egui::Window::new("Password required").collapsible(false).resizable(false).show(contexts.ctx_mut(), |ui| {
+                let response = ui.add(egui::TextEdit::singleline(password).password(true));
+                join = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                ui.horizontal(|ui| {
+                    join |= ui.button("Join").clicked();
+                    cancel = ui.button("Cancel").clicked();
+                });
+            });
+            if join {
+                config.password = Some(password.to_owned());
+                connect.send(ConnectEvent { conn: conn.to_owned() });
+            }
+            if join || cancel {
+                *password_prompt = None;
+            }
+        }
     }
 }
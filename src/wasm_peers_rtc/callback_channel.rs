@@ -1,12 +1,12 @@
-use std::error::Error;
+use std::{cell::RefCell, error::Error, rc::Rc};
 
-use js_sys::{Function, Promise, JSON};
+use js_sys::{ArrayBuffer, Function, Promise, Uint8Array, JSON};
 use serde::{Serialize, de::DeserializeOwned};
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{WebSocket, RtcDataChannel, MessageEvent};
+use web_sys::{BinaryType, WebSocket, RtcDataChannel, RtcDataChannelState, RtcDataChannelType, MessageEvent};
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue, closure::Closure, JsCast};
 
-use super::deque_channel::{JsDequeChannel, JsSender, JsReceiver};
+use super::{deque_channel::{JsDequeChannel, JsSender, JsReceiver}, send_queue::SendQueue, util::is_unreliable_packet};
 
 macro_rules! console_warn {
     ($($t:tt)*) => (warn(&format_args!($($t)*).to_string()))
@@ -26,13 +26,24 @@ pub trait CallbackChannel {
     fn set_onmessage(&self, value: Option<&Function>);
     fn set_onclose(&self, value: Option<&Function>);
     fn set_onerror(&self, value: Option<&Function>);
+    fn set_binary_type_arraybuffer(&self);
     fn send_with_str(&self, data: &str) -> Result<(), JsValue>;
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), JsValue>;
+    fn close(&self);
+    fn buffered_amount(&self) -> u32; // Bytes sent but not yet handed to the network
+}
+
+// Text messages carry JSON, binary messages carry raw packets
+#[derive(Clone)]
+pub enum ChannelMessage {
+    Text(JsValue),
+    Binary(Vec<u8>)
 }
 
 pub struct SendRecvCallbackChannel {
     channel: Box<dyn CallbackChannel>,
-    queue_sender: JsSender<JsValue>, // Enqueue newly received values
-    queue_receiver: JsReceiver<JsValue>
+    queue_sender: JsSender<ChannelMessage>, // Enqueue newly received values
+    queue_receiver: JsReceiver<ChannelMessage>
 }
 
 impl Clone for SendRecvCallbackChannel {
@@ -49,6 +60,7 @@ impl SendRecvCallbackChannel {
             queue_sender,
             queue_receiver
         };
+        ws.channel.set_binary_type_arraybuffer();
         let init_promise = Promise::new(&mut |resolve, _reject| {
             let on_open = Closure::<dyn FnMut()>::new(move || {
                 resolve.call0(&JsValue::UNDEFINED).unwrap();
@@ -74,8 +86,21 @@ impl SendRecvCallbackChannel {
 
             let sender = ws.queue_sender.clone();
             let on_message = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-                let value = JSON::parse(&e.data().as_string().unwrap()).unwrap();
-                sender.send(value).unwrap();
+                let data = e.data();
+                if let Some(text) = data.as_string() {
+                    match JSON::parse(&text) {
+                        Ok(value) => sender.send(ChannelMessage::Text(value)).unwrap(),
+                        Err(e) => {
+                            // The peer is broken or hostile, either way we're done talking to it
+                            console_warn!("Closing channel after malformed message: {:?}", e);
+                            sender.close().unwrap();
+                        }
+                    }
+                } else if data.is_instance_of::<ArrayBuffer>() {
+                    sender.send(ChannelMessage::Binary(Uint8Array::new(&data).to_vec())).unwrap();
+                } else {
+                    console_warn!("Dropping message of unexpected type: {:?}", data);
+                }
             });
             ws.channel.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
             on_message.forget();
@@ -90,23 +115,26 @@ impl SendRecvCallbackChannel {
         Ok(())
     }
 
+    pub fn send_bytes(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.channel.send_with_u8_array(data)
+    }
+
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<T, JsValue> {
-        self.queue_receiver
-            .recv()
-            .await
-            .map(|value| {
-                serde_wasm_bindgen::from_value(value.clone()).map_err(|e| {
-                    let str = format!("Failed to deserialize value `{:?}`: {}", value, e);
-                    JsValue::from_str(&str)
-                })
-            })?
-    }
-
-    pub fn drain<T: DeserializeOwned>(&mut self) -> Result<Vec<T>, Box<dyn Error>> {
+        match self.queue_receiver.recv().await? {
+            ChannelMessage::Text(value) => serde_wasm_bindgen::from_value(value.clone()).map_err(|e| {
+                let str = format!("Failed to deserialize value `{:?}`: {}", value, e);
+                JsValue::from_str(&str)
+            }),
+            ChannelMessage::Binary(_) => Err(JsValue::from_str("Expected text message, got binary")),
+        }
+    }
+
+    pub fn drain_bytes(&mut self) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
         self.queue_receiver.drain()
             .into_iter()
-            .map(|m| {
-                serde_wasm_bindgen::from_value(m).map_err(|e| Box::new(e) as Box<dyn Error>)
+            .map(|m| match m {
+                ChannelMessage::Binary(data) => Ok(data),
+                ChannelMessage::Text(_) => Err("Expected binary message, got text".into()),
             })
             .collect()
     }
@@ -114,6 +142,74 @@ impl SendRecvCallbackChannel {
     pub fn is_closed(&self) -> bool {
         self.queue_receiver.is_closed()
     }
+
+    // onclose fires once the channel actually went down
+    pub fn close(&self) {
+        self.channel.close();
+    }
+
+    pub fn buffered_amount(&self) -> u32 {
+        self.channel.buffered_amount()
+    }
+}
+
+// The reliable/ordered and unreliable/unordered data channels of one peer.
+// renet packets are routed to the one matching the send type of their renet channel.
+#[derive(Clone)]
+pub struct DataChannels {
+    reliable: SendRecvCallbackChannel,
+    unreliable: SendRecvCallbackChannel,
+    queue: Rc<RefCell<SendQueue>>
+}
+
+impl DataChannels {
+    pub fn new(reliable: SendRecvCallbackChannel, unreliable: SendRecvCallbackChannel) -> DataChannels {
+        DataChannels { reliable, unreliable, queue: Rc::new(RefCell::new(SendQueue::default())) }
+    }
+
+    // Fails once the peer fell too far behind on reliable packets
+    pub fn send_packet(&mut self, packet: &[u8]) -> Result<(), JsValue> {
+        let mut queue = self.queue.borrow_mut();
+        if is_unreliable_packet(packet) {
+            queue.send_unreliable(packet, self.unreliable.buffered_amount(), |packet| self.unreliable.send_bytes(packet))
+        } else {
+            queue.push_reliable(packet).map_err(|e| JsValue::from_str(&e))?;
+            queue.flush(|| self.reliable.buffered_amount(), |packet| self.reliable.send_bytes(packet))
+        }
+    }
+
+    // Sends what congestion held back earlier, call once per frame
+    pub fn flush(&mut self) -> Result<(), JsValue> {
+        self.queue.borrow_mut().flush(|| self.reliable.buffered_amount(), |packet| self.reliable.send_bytes(packet))
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.queue.borrow().queued_packets()
+    }
+
+    pub fn dropped_packets(&self) -> u64 {
+        self.queue.borrow().dropped_packets()
+    }
+
+    pub fn drain_packets(&mut self) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let mut packets = self.reliable.drain_bytes()?;
+        packets.extend(self.unreliable.drain_bytes()?);
+        Ok(packets)
+    }
+
+    // Losing either channel makes the peer unusable
+    pub fn is_closed(&self) -> bool {
+        self.reliable.is_closed() || self.unreliable.is_closed()
+    }
+
+    pub fn close(&self) {
+        self.reliable.close();
+        self.unreliable.close();
+    }
+
+    pub fn buffered_amount(&self) -> u32 {
+        self.reliable.buffered_amount() + self.unreliable.buffered_amount()
+    }
 }
 
 impl CallbackChannel for WebSocket {
@@ -137,9 +233,27 @@ impl CallbackChannel for WebSocket {
         self.set_onerror(value);
     }
 
+    fn set_binary_type_arraybuffer(&self) {
+        self.set_binary_type(BinaryType::Arraybuffer);
+    }
+
     fn send_with_str(&self, data: &str) -> Result<(), JsValue> {
         self.send_with_str(data)
     }
+
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.send_with_u8_array(data)
+    }
+
+    fn close(&self) {
+        if let Err(e) = self.close() {
+            console_warn!("Failed to close websocket: {:?}", e);
+        }
+    }
+
+    fn buffered_amount(&self) -> u32 {
+        self.buffered_amount()
+    }
 }
 
 impl CallbackChannel for RtcDataChannel {
@@ -149,6 +263,10 @@ impl CallbackChannel for RtcDataChannel {
 
     fn set_onopen(&self, value: Option<&Function>) {
         self.set_onopen(value);
+        // A peer's channels open together, so this one may have opened while we awaited another
+        if let (Some(f), RtcDataChannelState::Open) = (value, self.ready_state()) {
+            f.call0(&JsValue::UNDEFINED).unwrap();
+        }
     }
 
     fn set_onmessage(&self, value: Option<&Function>) {
@@ -163,7 +281,23 @@ impl CallbackChannel for RtcDataChannel {
         self.set_onerror(value);
     }
 
+    fn set_binary_type_arraybuffer(&self) {
+        self.set_binary_type(RtcDataChannelType::Arraybuffer);
+    }
+
     fn send_with_str(&self, data: &str) -> Result<(), JsValue> {
         self.send_with_str(data)
     }
+
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.send_with_u8_array(data)
+    }
+
+    fn close(&self) {
+        self.close();
+    }
+
+    fn buffered_amount(&self) -> u32 {
+        self.buffered_amount()
+    }
 }
@@ -1,12 +1,15 @@
-use std::{rc::Rc, cell::RefCell, collections::HashMap};
+use std::{rc::Rc, cell::{Cell, RefCell}, collections::HashMap};
 
-use bevy::prelude::*;
+use bevy::{ecs::system::SystemState, prelude::*};
+use bevy_egui::{egui, EguiContexts};
 use bevy_inspector_egui::quick::StateInspectorPlugin;
-use bevy_replicon::replicon_core::NetworkChannels;
+use bevy_renet::{RenetClientPlugin, RenetReceive, RenetSend};
+use bevy_replicon::{client::ClientSet, replicon_core::{replication_rules::Replication, NetworkChannels}};
 use renet::{RenetClient, ConnectionConfig};
+use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::spawn_local;
 
-use super::{webrtc::{AsyncWebRtcBrowser, AsyncWebRtcClient}, signaling::{ServerEntry, ConnectionId}, callback_channel::SendRecvCallbackChannel};
+use super::{config::{NetworkConfig, PROTOCOL_VERSION}, webrtc::{AsyncWebRtcBrowser, AsyncWebRtcClient, ConnectError}, signaling::{ServerEntry, ConnectionId}, callback_channel::DataChannels, client_pump, simulator::NetworkSimulator, util::Backoff};
 
 pub struct WebRtcClientPlugin {
     pub is_headless: bool
@@ -21,9 +24,25 @@ impl Plugin for WebRtcClientPlugin {
             app.add_plugins(StateInspectorPlugin::<WebRtcBrowserState>::new());
             app.add_plugins(StateInspectorPlugin::<WebRtcClientState>::new());
         }
+        app.init_resource::<ClientReconnect>();
+        app.add_event::<ReconnectEvent>();
+        app.add_event::<LeaveServerEvent>();
         app.add_systems(PreUpdate, (Self::update_browser_state, Self::update_client_state));
         app.add_systems(OnEnter(WebRtcClientState::Connected), Self::client_connected);
-        app.add_systems(Update, Self::update_client_packets.run_if(in_state(WebRtcClientState::Connected))); // TODO fix 1 frame delay by moving to PreUpdate, using system sets to run after update_client_state but before renet handles packets
+        app.add_systems(OnExit(WebRtcClientState::Connected), Self::client_disconnected);
+        app.add_systems(OnEnter(WebRtcClientState::ConnectionLost), Self::connection_lost);
+        app.add_systems(OnEnter(WebRtcClientState::Failed), Self::connection_lost);
+        app.add_systems(Update, Self::reconnect.run_if(in_state(WebRtcClientState::ConnectionLost).or_else(in_state(WebRtcClientState::Failed))));
+        app.add_systems(Update, Self::leave_server.run_if(not(in_state(WebRtcClientState::Disconnected))));
+        if !self.is_headless {
+            app.add_systems(Update, Self::show_connection_lost.run_if(in_state(WebRtcClientState::ConnectionLost).or_else(in_state(WebRtcClientState::Failed))));
+            app.add_systems(Update, Self::show_refused.run_if(in_state(WebRtcClientState::Refused)));
+            app.add_systems(Update, Self::show_connection_interrupted.run_if(in_state(WebRtcClientState::Connected)));
+        }
+        app.configure_sets(PreUpdate, WebRtcClientSet::Receive.in_set(RenetReceive).after(RenetClientPlugin::update_system).before(ClientSet::Receive));
+        app.configure_sets(PostUpdate, WebRtcClientSet::Send.in_set(RenetSend).after(ClientSet::Send));
+        app.add_systems(PreUpdate, Self::receive_packets.in_set(WebRtcClientSet::Receive).after(Self::update_client_state).run_if(in_state(WebRtcClientState::Connected)));
+        app.add_systems(PostUpdate, Self::send_packets.in_set(WebRtcClientSet::Send).run_if(in_state(WebRtcClientState::Connected)));
     }
 }
 
@@ -61,8 +80,11 @@ impl WebRtcClientPlugin {
     ) {
         let removed = client.is_none() && *exists;
         let changed = client.as_ref().map_or(false, |b| b.is_changed());
-        if removed || changed || *state == WebRtcClientState::Connecting {
+        if removed || changed || *state != WebRtcClientState::Disconnected {
             let next = match &client {
+                Some(c) if c.refusal().is_some() => WebRtcClientState::Refused,
+                Some(c) if c.failure().is_some() => WebRtcClientState::Failed,
+                Some(c) if c.is_lost() => WebRtcClientState::ConnectionLost,
                 Some(c) if c.channel().is_some() => WebRtcClientState::Connected,
                 Some(_) => WebRtcClientState::Connecting,
                 None => WebRtcClientState::Disconnected,
@@ -81,33 +103,165 @@ impl WebRtcClientPlugin {
             client_channels_config: network_channels.get_client_configs(),
             ..Default::default()
         };
-        let client = RenetClient::new(connection_config);
+        // The data channels are open and the handshake is done by the time we get here
+        let mut client = RenetClient::new(connection_config);
+        client.set_connected();
         world.insert_resource(client);
+        world.resource_mut::<ClientReconnect>().backoff = Backoff::default();
+        // Nothing from an earlier connection to the same server may leak into this one
+        let server_id = world.non_send_resource::<WebRtcClient>().server_id().clone();
+        world.resource_mut::<NetworkSimulator>().remove(&server_id);
+    }
+
+    // Forget everything the server replicated, so a reconnect starts from a clean slate
+    fn client_disconnected(mut commands: Commands, replicated: Query<Entity, With<Replication>>) {
+        commands.remove_resource::<RenetClient>();
+        for entity in replicated.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+
+    fn connection_lost(mut reconnect: ResMut<ClientReconnect>) {
+        let delay = reconnect.backoff.next_delay_ms() as f32 / 1000.;
+        reconnect.timer = Timer::from_seconds(delay, TimerMode::Once);
+        warn!("Client: not connected, reconnecting in {}s", delay);
     }
 
-    fn update_client_packets(
-        rtc_client: NonSendMut<WebRtcClient>,
-        mut renet_client: ResMut<RenetClient>
+    fn reconnect(
+        mut client: NonSendMut<WebRtcClient>,
+        config: Res<NetworkConfig>,
+        time: Res<Time>,
+        mut reconnect: ResMut<ClientReconnect>,
+        mut events: EventReader<ReconnectEvent>
     ) {
-        // TODO handle .set_connecting()?
-        renet_client.set_connected();
-
-        // TODO handle transport-disconnect
-        
-        // Handle incoming packets
-        let packets: Vec<Vec<u8>> = rtc_client.channel().unwrap().drain().unwrap();
-        for packet in packets {
-            renet_client.process_packet(&packet);
+        reconnect.timer.tick(time.delta());
+        let requested = events.read().count() > 0;
+        if requested || (reconnect.auto && reconnect.timer.finished()) {
+            info!("Client: reconnecting to server {}", client.server_id());
+            *client = client.reconnect(config.clone());
+        }
+    }
+
+    // Hang up on the server and go back to the server list
+    fn leave_server(world: &mut World) {
+        let mut events = SystemState::<EventReader<LeaveServerEvent>>::new(world);
+        if events.get_mut(world).read().count() == 0 {
+            return;
+        }
+        if let Some(client) = world.remove_non_send_resource::<WebRtcClient>() {
+            info!("Client: leaving server {}", client.server_id());
+            client.close();
         }
+        let config = world.resource::<NetworkConfig>().clone();
+        world.insert_non_send_resource(WebRtcBrowser::new(config));
+    }
+
+    fn show_connection_lost(
+        mut contexts: EguiContexts,
+        client: NonSend<WebRtcClient>,
+        mut reconnect: ResMut<ClientReconnect>,
+        mut writer: EventWriter<ReconnectEvent>,
+        mut leave: EventWriter<LeaveServerEvent>
+    ) {
+        let (title, reason) = match client.failure() {
+            Some(failure) => ("Connection failed", format!("Couldn't connect to the server: {}", failure)),
+            None => ("Connection lost", "The connection to the server was lost.".to_owned()),
+        };
+        egui::Window::new(title).id(egui::Id::new("connection_lost")).collapsible(false).resizable(false).show(contexts.ctx_mut(), |ui| {
+            ui.label(reason);
+            if reconnect.auto {
+                ui.label(format!("Reconnecting in {:.0}s...", reconnect.timer.remaining_secs().ceil()));
+            }
+            ui.checkbox(&mut reconnect.auto, "Reconnect automatically");
+            ui.horizontal(|ui| {
+                if ui.button("Reconnect now").clicked() {
+                    writer.send(ReconnectEvent);
+                }
+                if ui.button("Back to server list").clicked() {
+                    leave.send(LeaveServerEvent);
+                }
+            });
+        });
+    }
+
+    fn show_refused(mut contexts: EguiContexts, client: NonSend<WebRtcClient>, mut leave: EventWriter<LeaveServerEvent>) {
+        egui::Window::new("Connection refused").collapsible(false).resizable(false).show(contexts.ctx_mut(), |ui| {
+            ui.label(client.refusal().unwrap_or_default());
+            if ui.button("Back to server list").clicked() {
+                leave.send(LeaveServerEvent);
+            }
+        });
+    }
 
-        // Handle outgoing packets
-        let packets = renet_client.get_packets_to_send();
-        for packet in packets {
-            rtc_client.channel().unwrap().send(packet).unwrap();
+    // The connection dropped, but may still recover through an ICE restart
+    fn show_connection_interrupted(mut contexts: EguiContexts, client: NonSend<WebRtcClient>) {
+        if !client.is_restarting() {
+            return;
         }
+        egui::Window::new("Connection interrupted").collapsible(false).resizable(false).show(contexts.ctx_mut(), |ui| {
+            ui.label("Trying to restore the connection to the server...");
+        });
+    }
+
+    // Feeds renet what arrived since last frame, right after it updated, so the game sees it this frame
+    fn receive_packets(
+        rtc_client: Option<NonSend<WebRtcClient>>, // Gone for the rest of the frame after leaving
+        mut renet_client: ResMut<RenetClient>,
+        mut simulator: ResMut<NetworkSimulator>,
+        time: Res<Time<Real>>
+    ) {
+        let Some(rtc_client) = rtc_client else {
+            return;
+        };
+        let Some(mut channels) = rtc_client.channel() else {
+            return;
+        };
+        client_pump::receive_packets(&mut channels, rtc_client.is_restarting(), &mut renet_client, &mut simulator, rtc_client.server_id(), time.elapsed());
+    }
+
+    // Sends whatever renet queued this frame, after replicon wrote the frame's events and acks
+    fn send_packets(
+        rtc_client: Option<NonSend<WebRtcClient>>,
+        mut renet_client: ResMut<RenetClient>,
+        mut simulator: ResMut<NetworkSimulator>,
+        time: Res<Time<Real>>
+    ) {
+        let Some(rtc_client) = rtc_client else {
+            return;
+        };
+        let Some(mut channels) = rtc_client.channel() else {
+            return;
+        };
+        client_pump::send_packets(&mut channels, &mut renet_client, &mut simulator, rtc_client.server_id(), time.elapsed());
     }
 }
 
+// Where the transport hands packets to renet and takes them back, around renet's and replicon's own sets
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WebRtcClientSet {
+    Receive, // In PreUpdate
+    Send // In PostUpdate
+}
+
+#[derive(Resource)]
+pub struct ClientReconnect {
+    pub auto: bool,
+    backoff: Backoff,
+    timer: Timer
+}
+
+impl Default for ClientReconnect {
+    fn default() -> Self {
+        Self { auto: true, backoff: Backoff::default(), timer: Timer::default() }
+    }
+}
+
+#[derive(Event)]
+pub struct ReconnectEvent;
+
+#[derive(Event)]
+pub struct LeaveServerEvent;
+
 #[derive(States, Debug, Default, Hash, Eq, PartialEq, Clone, Reflect)]
 pub enum WebRtcBrowserState {
     #[default]
@@ -121,22 +275,29 @@ pub enum WebRtcClientState {
     #[default]
     Disconnected,
     Connecting,
-    Connected
+    Connected,
+    ConnectionLost,
+    Failed, // Couldn't connect, see WebRtcClient::failure()
+    Refused // The server won't have us, see WebRtcClient::refusal()
 }
 
 #[derive(Clone)]
 pub struct WebRtcBrowser {
-    browser: Rc<RefCell<Option<AsyncWebRtcBrowser>>>
+    browser: Rc<RefCell<Option<AsyncWebRtcBrowser>>>,
+    failed: Rc<Cell<bool>>
 }
 
 impl WebRtcBrowser {
-    pub fn new(signaling_url: String) -> WebRtcBrowser {
-        let browser = WebRtcBrowser { browser: Rc::new(RefCell::new(None)) };
+    pub fn new(config: NetworkConfig) -> WebRtcBrowser {
+        let browser = WebRtcBrowser { browser: Rc::new(RefCell::new(None)), failed: Rc::new(Cell::new(false)) };
         let browser_clone = browser.clone();
         spawn_local(async move {
-            match AsyncWebRtcBrowser::new(&signaling_url).await {
+            match AsyncWebRtcBrowser::new(&config).await {
                 Ok(b) => {info!("Storing Browser result"); *browser_clone.browser.borrow_mut() = Some(b)},
-                Err(e) => warn!("Error creating AsyncWebRtcBrowser: {:?}", e),
+                Err(e) => {
+                    warn!("Error creating AsyncWebRtcBrowser: {:?}", e);
+                    browser_clone.failed.set(true);
+                }
             }
         });
         browser
@@ -146,28 +307,133 @@ impl WebRtcBrowser {
         self.browser.borrow().as_ref().map(|browser| browser.servers())
     }
 
-    pub fn connect(self, server_id: ConnectionId) -> WebRtcClient {
-        let client = WebRtcClient { client: Rc::new(RefCell::new(None)) };
+    pub fn refresh(&self) {
+        if let Some(browser) = self.browser.borrow_mut().as_mut() {
+            if let Err(e) = browser.refresh() {
+                warn!("Error refreshing server list: {:?}", e);
+            }
+        }
+    }
+
+    pub fn close(&self) {
+        if let Some(browser) = self.browser.borrow().as_ref() {
+            browser.close();
+        }
+    }
+
+    // How many server lists arrived, 0 until connected to the signaling server
+    pub fn lists_received(&self) -> u32 {
+        self.browser.borrow().as_ref().map_or(0, |browser| browser.lists_received())
+    }
+
+    // Failed to reach the signaling server, or lost it afterwards
+    pub fn is_closed(&self) -> bool {
+        self.failed.get() || self.browser.borrow().as_ref().is_some_and(|browser| browser.is_closed())
+    }
+
+    pub fn connect(self, server_id: ConnectionId, password: Option<String>) -> WebRtcClient {
+        let client = WebRtcClient::new(server_id.clone());
         let client_clone = client.clone();
-        self.browser.take().map(|browser| {
+        if let Some(browser) = self.browser.take() {
             spawn_local(async move {
-                match browser.connect(server_id).await {
-                    Ok(c) => *client_clone.client.borrow_mut() = Some(c),
-                    Err(e) => warn!("Error creating AsyncWebRtcClient: {:?}", e),
-                }
-            })
-        });
+                let result = browser.connect(server_id, password).await;
+                client_clone.connected(result);
+            });
+        } else {
+            client.fail("Not connected to the signaling server yet".to_owned());
+        }
         client
     }
 }
 
 #[derive(Clone)]
 pub struct WebRtcClient {
-    client: Rc<RefCell<Option<AsyncWebRtcClient>>>
+    client: Rc<RefCell<Option<AsyncWebRtcClient>>>,
+    server_id: ConnectionId,
+    failure: Rc<RefCell<Option<String>>>,
+    refusal: Rc<RefCell<Option<String>>>
 }
 
 impl WebRtcClient {
-    pub fn channel(&self) -> Option<SendRecvCallbackChannel> {
+    fn new(server_id: ConnectionId) -> WebRtcClient {
+        WebRtcClient { client: Rc::new(RefCell::new(None)), server_id, failure: Rc::new(RefCell::new(None)), refusal: Rc::new(RefCell::new(None)) }
+    }
+
+    pub fn channel(&self) -> Option<DataChannels> {
         self.client.borrow().as_deref().cloned()
     }
+
+    pub fn server_id(&self) -> &ConnectionId {
+        &self.server_id
+    }
+
+    // Why the server turned us away, in which case reconnecting is pointless
+    pub fn refusal(&self) -> Option<String> {
+        self.refusal.borrow().clone()
+    }
+
+    // Why connecting failed, e.g. a negotiation step timing out
+    pub fn failure(&self) -> Option<String> {
+        self.failure.borrow().clone()
+    }
+
+    // The connection dropped after being established
+    pub fn is_lost(&self) -> bool {
+        self.channel().is_some_and(|c| c.is_closed())
+    }
+
+    // The connection dropped and is being restarted, see AsyncWebRtcClient::monitor_ice()
+    pub fn is_restarting(&self) -> bool {
+        self.client.borrow().as_ref().is_some_and(|c| c.is_restarting())
+    }
+
+    fn fail(&self, reason: String) {
+        warn!("Client: failed to connect to {}: {}", self.server_id, reason);
+        *self.failure.borrow_mut() = Some(reason);
+    }
+
+    pub fn close(&self) {
+        if let Some(channels) = self.channel() {
+            channels.close();
+        }
+    }
+
+    // Connect to the same server again, through a fresh signaling connection
+    pub fn reconnect(&self, config: NetworkConfig) -> WebRtcClient {
+        let client = WebRtcClient::new(self.server_id.clone());
+        let client_clone = client.clone();
+        spawn_local(async move {
+            let browser = match AsyncWebRtcBrowser::new(&config).await {
+                Ok(browser) => browser,
+                Err(e) => {
+                    client_clone.fail(format!("Couldn't reach the signaling server: {}", describe(&e)));
+                    return;
+                }
+            };
+            let result = browser.connect(client_clone.server_id.clone(), config.password).await;
+            client_clone.connected(result);
+        });
+        client
+    }
+
+    fn connected(&self, result: Result<AsyncWebRtcClient, ConnectError>) {
+        match result {
+            Ok(c) => *self.client.borrow_mut() = Some(c),
+            Err(ConnectError::Incompatible { server_version }) => {
+                let reason = format!("The server runs protocol version {}, but this client speaks version {}.", server_version, PROTOCOL_VERSION);
+                warn!("Server {} refused us: {}", self.server_id, reason);
+                *self.refusal.borrow_mut() = Some(reason);
+            }
+            Err(ConnectError::WrongPassword) => {
+                warn!("Server {} refused us: wrong password", self.server_id);
+                *self.refusal.borrow_mut() = Some("Wrong password".to_owned());
+            }
+            Err(ConnectError::Failed(e)) => self.fail(describe(&e)),
+        }
+    }
+}
+
+// JS errors are mostly strings, but may be anything
+fn describe(e: &JsValue) -> String {
+    e.as_string().unwrap_or_else(|| format!("{:?}", e))
 }
@@ -0,0 +1,119 @@
+// Moves packets between a client's data channels and renet, kept apart from the wasm-only plugin so it can be tested natively
+use std::time::Duration;
+
+use bevy::log::warn;
+use renet::RenetClient;
+
+use super::{callback_channel::DataChannels, simulator::NetworkSimulator};
+
+// Feeds renet what arrived since last frame. `restarting` while an ICE restart tries to restore the connection
+pub fn receive_packets(
+    channels: &mut DataChannels,
+    restarting: bool,
+    renet_client: &mut RenetClient,
+    simulator: &mut NetworkSimulator,
+    server_id: &str,
+    now: Duration
+) {
+    // Transport-disconnect, picked up by the client state next frame
+    if channels.is_closed() {
+        renet_client.disconnect_due_to_transport();
+        return;
+    }
+
+    // Renet, and replicon with it, holds off while an ICE restart restores the connection
+    if restarting && renet_client.is_connected() {
+        renet_client.set_connecting();
+    } else if !restarting && renet_client.is_connecting() {
+        renet_client.set_connected();
+    }
+
+    match channels.drain_packets() {
+        Ok(packets) => for packet in simulator.incoming(server_id, now, packets) {
+            renet_client.process_packet(&packet);
+        },
+        Err(e) => warn!("Client: failed to receive packets: {:?}", e),
+    }
+}
+
+// Sends whatever renet queued this frame, disconnecting it if the data channels won't take it
+pub fn send_packets(channels: &mut DataChannels, renet_client: &mut RenetClient, simulator: &mut NetworkSimulator, server_id: &str, now: Duration) {
+    if channels.is_closed() {
+        return;
+    }
+
+    // Whatever congestion held back earlier goes first
+    let packets = simulator.outgoing(server_id, now, renet_client.get_packets_to_send());
+    let sent = channels.flush().and_then(|_| packets.iter().try_for_each(|packet| channels.send_packet(packet)));
+    if let Err(e) = sent {
+        warn!("Client: failed to send packet: {:?}", e);
+        renet_client.disconnect_due_to_transport();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renet::{ClientId, ConnectionConfig, DefaultChannel, DisconnectReason, RenetServer};
+
+    use super::{super::{config::NetworkConditions, native::loopback::{block_on, data_channels_pair}}, *};
+
+    const SERVER_ID: &str = "server";
+
+    // A connected renet client on our end of the data channels, and the server's end
+    fn connected() -> (RenetClient, DataChannels, DataChannels, NetworkSimulator) {
+        let (channels, server) = block_on(data_channels_pair());
+        let mut client = RenetClient::new(ConnectionConfig::default());
+        client.set_connected();
+        (client, channels, server, NetworkSimulator::new(NetworkConditions::default()))
+    }
+
+    #[test]
+    fn pumps_packets_both_ways() {
+        let (mut client, mut channels, mut server_channels, mut simulator) = connected();
+        let mut server = RenetServer::new(ConnectionConfig::default());
+        let client_id = ClientId::from_raw(1);
+        server.add_connection(client_id);
+
+        client.send_message(DefaultChannel::ReliableOrdered, "ping");
+        client.send_message(DefaultChannel::Unreliable, "ping");
+        send_packets(&mut channels, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+        for packet in server_channels.drain_packets().unwrap() {
+            server.process_packet_from(&packet, client_id).unwrap();
+        }
+        assert_eq!(server.receive_message(client_id, DefaultChannel::ReliableOrdered).as_deref(), Some(&b"ping"[..]));
+        assert_eq!(server.receive_message(client_id, DefaultChannel::Unreliable).as_deref(), Some(&b"ping"[..]));
+
+        server.send_message(client_id, DefaultChannel::ReliableOrdered, "pong");
+        server.send_message(client_id, DefaultChannel::Unreliable, "pong");
+        for packet in server.get_packets_to_send(client_id).unwrap() {
+            server_channels.send_packet(&packet).unwrap();
+        }
+        receive_packets(&mut channels, false, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+        assert_eq!(client.receive_message(DefaultChannel::ReliableOrdered).as_deref(), Some(&b"pong"[..]));
+        assert_eq!(client.receive_message(DefaultChannel::Unreliable).as_deref(), Some(&b"pong"[..]));
+    }
+
+    #[test]
+    fn closed_data_channels_disconnect_renet() {
+        let (mut client, mut channels, server_channels, mut simulator) = connected();
+        server_channels.close();
+        receive_packets(&mut channels, false, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+        assert!(client.is_disconnected());
+        assert!(matches!(client.disconnect_reason(), Some(DisconnectReason::Transport)));
+
+        // Nothing more goes out on a closed connection
+        client.send_message(DefaultChannel::ReliableOrdered, "ping");
+        send_packets(&mut channels, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+    }
+
+    #[test]
+    fn ice_restart_holds_renet_off() {
+        let (mut client, mut channels, _server_channels, mut simulator) = connected();
+        receive_packets(&mut channels, true, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+        assert!(client.is_connecting());
+        receive_packets(&mut channels, true, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+        assert!(client.is_connecting());
+        receive_packets(&mut channels, false, &mut client, &mut simulator, SERVER_ID, Duration::ZERO);
+        assert!(client.is_connected());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt, str::FromStr};
+
+use bevy::prelude::*;
+use clap::Args;
+
+use super::signaling::ServerEntry;
+
+pub const DEFAULT_SIGNALING_URL: &str = "wss://rose-signalling.webpubsub.azure.com/client/hubs/onlineservers";
+pub const DEFAULT_GAME_NAME: &str = "some-game";
+pub const DEFAULT_SERVER_NAME: &str = "my-server";
+pub const DEFAULT_MAX_PLAYERS: u32 = 8;
+pub const DEFAULT_GAME_MODE: &str = "classic";
+pub const DEFAULT_MAP: &str = "arena";
+// Bump whenever client and server stop understanding each other
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const DEFAULT_NEGOTIATION_TIMEOUT_MS: u32 = 10000;
+pub const DEFAULT_ICE_RESTART_TIMEOUT_MS: u32 = 15000;
+pub const DEFAULT_ICE_SERVERS: [&str; 4] = [
+    "stun:stun1.l.google.com:19302",
+    "stun:stun2.l.google.com:19302",
+    "stun:stun3.l.google.com:19302",
+    "stun:stun4.l.google.com:19302",
+];
+
+#[derive(Resource, Args, Clone, Debug)]
+pub struct NetworkConfig {
+    /// Websocket URL of the signaling server
+    #[arg(long = "signaling-url", default_value = DEFAULT_SIGNALING_URL)]
+    pub signaling_url: String,
+    /// Game name to register under and to filter the server list by
+    #[arg(long = "game-name", default_value = DEFAULT_GAME_NAME)]
+    pub game_name: String,
+    /// Name shown in the server browser when hosting
+    #[arg(long = "server-name", default_value = DEFAULT_SERVER_NAME)]
+    pub server_name: String,
+    /// Player capacity advertised in the server browser when hosting
+    #[arg(long = "max-players", default_value_t = DEFAULT_MAX_PLAYERS)]
+    pub max_players: u32,
+    /// Game mode advertised in the server browser when hosting
+    #[arg(long = "mode", default_value = DEFAULT_GAME_MODE)]
+    pub mode: String,
+    /// Map advertised in the server browser when hosting
+    #[arg(long = "map", default_value = DEFAULT_MAP)]
+    pub map: String,
+    /// Password required to join when hosting, and presented to servers when joining
+    #[arg(long = "password")]
+    pub password: Option<String>,
+    /// STUN/TURN server; repeat for several
+    #[arg(long = "ice-server", value_name = "[USERNAME:CREDENTIAL@]URL", default_values = DEFAULT_ICE_SERVERS)]
+    pub ice_servers: Vec<IceServer>,
+    /// How long each step of connecting a peer (offer, answer, data channels, handshake) may take
+    #[arg(long = "negotiation-timeout", value_name = "MS", default_value_t = DEFAULT_NEGOTIATION_TIMEOUT_MS)]
+    pub negotiation_timeout_ms: u32,
+    /// How long an interrupted peer connection may take to recover through an ICE restart before it's dropped
+    #[arg(long = "ice-restart-timeout", value_name = "MS", default_value_t = DEFAULT_ICE_RESTART_TIMEOUT_MS)]
+    pub ice_restart_timeout_ms: u32,
+    #[command(flatten)]
+    pub conditions: NetworkConditions,
+}
+
+// Artificial network conditions for testing, applied between the data channels and renet
+#[derive(Args, Clone, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Simulated one-way latency added to every packet
+    #[arg(long = "sim-latency", value_name = "MS", default_value_t = 0)]
+    pub latency_ms: u32,
+    /// Simulated random variation of the latency, in both directions
+    #[arg(long = "sim-jitter", value_name = "MS", default_value_t = 0)]
+    pub jitter_ms: u32,
+    /// Simulated share of packets dropped
+    #[arg(long = "sim-loss", value_name = "PERCENT", default_value_t = 0.)]
+    pub loss_percent: f32,
+    /// Simulated bandwidth cap per direction and connection, 0 for none
+    #[arg(long = "sim-bandwidth", value_name = "KBIT/S", default_value_t = 0)]
+    pub bandwidth_kbps: u32,
+    /// Seed for the simulated jitter and loss, for reproducible runs
+    #[arg(long = "sim-seed")]
+    pub seed: Option<u64>,
+}
+
+impl NetworkConditions {
+    pub fn is_active(&self) -> bool {
+        self.latency_ms > 0 || self.jitter_ms > 0 || self.loss_percent > 0. || self.bandwidth_kbps > 0
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            signaling_url: DEFAULT_SIGNALING_URL.to_owned(),
+            game_name: DEFAULT_GAME_NAME.to_owned(),
+            server_name: DEFAULT_SERVER_NAME.to_owned(),
+            max_players: DEFAULT_MAX_PLAYERS,
+            mode: DEFAULT_GAME_MODE.to_owned(),
+            map: DEFAULT_MAP.to_owned(),
+            password: None,
+            ice_servers: DEFAULT_ICE_SERVERS.iter().map(|s| s.parse().unwrap()).collect(),
+            negotiation_timeout_ms: DEFAULT_NEGOTIATION_TIMEOUT_MS,
+            ice_restart_timeout_ms: DEFAULT_ICE_RESTART_TIMEOUT_MS,
+            conditions: NetworkConditions::default(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    // How this server shows up in the browser, before anyone joined
+    pub fn server_entry(&self) -> ServerEntry {
+        ServerEntry {
+            name: self.server_name.to_owned(),
+            game: self.game_name.to_owned(),
+            players: 0,
+            max_players: self.max_players,
+            mode: self.mode.to_owned(),
+            map: self.map.to_owned(),
+            version: PROTOCOL_VERSION,
+            password: self.password.is_some(),
+        }
+    }
+
+    // Override settings from `key=value` pairs, as found in a URL query string
+    #[cfg(target_arch = "wasm32")]
+    pub fn apply_query(&mut self, query: &str) -> Result<(), String> {
+        let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).map_err(|e| e.to_string())?;
+        let mut ice_servers = Vec::new();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "signaling" => self.signaling_url = value,
+                "game" => self.game_name = value,
+                "server" => self.server_name = value,
+                "password" => self.password = Some(value), // Lets invite links carry the password
+                "ice" => ice_servers.push(value.parse()?),
+                "timeout" => self.negotiation_timeout_ms = value.parse().map_err(|e| format!("Invalid timeout `{}`: {}", value, e))?,
+                "latency" => self.conditions.latency_ms = value.parse().map_err(|e| format!("Invalid latency `{}`: {}", value, e))?,
+                "jitter" => self.conditions.jitter_ms = value.parse().map_err(|e| format!("Invalid jitter `{}`: {}", value, e))?,
+                "loss" => self.conditions.loss_percent = value.parse().map_err(|e| format!("Invalid loss `{}`: {}", value, e))?,
+                "bandwidth" => self.conditions.bandwidth_kbps = value.parse().map_err(|e| format!("Invalid bandwidth `{}`: {}", value, e))?,
+                _ => {}
+            }
+        }
+        if !ice_servers.is_empty() {
+            self.ice_servers = ice_servers;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IceServer {
+    pub url: String,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+impl FromStr for IceServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // URLs never contain '@', so anything before the last one is credentials
+        let (credentials, url) = match s.trim().rsplit_once('@') {
+            Some((credentials, url)) => (Some(credentials), url),
+            None => (None, s.trim()),
+        };
+        if !(url.starts_with("stun:") || url.starts_with("turn:") || url.starts_with("turns:")) {
+            return Err(format!("ICE server `{}` must start with stun:, turn: or turns:", url));
+        }
+        let (username, credential) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((username, credential))) => (Some(username.to_owned()), Some(credential.to_owned())),
+            Some(None) => return Err(format!("ICE server credentials for `{}` must be USERNAME:CREDENTIAL", url)),
+            None => (None, None),
+        };
+        Ok(IceServer { url: url.to_owned(), username, credential })
+    }
+}
+
+impl fmt::Display for IceServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(username), Some(credential)) = (&self.username, &self.credential) {
+            write!(f, "{}:{}@", username, credential)?;
+        }
+        write!(f, "{}", self.url)
+    }
+}
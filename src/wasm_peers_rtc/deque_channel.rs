@@ -24,6 +24,10 @@ impl<T> JsSender<T> {
         channel.closed = true;
         Ok(())
     }
+
+    pub fn same_channel(&self, other: &JsSender<T>) -> bool {
+        Rc::ptr_eq(&self.channel, &other.channel)
+    }
 }
 
 #[derive(Clone)]
@@ -1,8 +1,23 @@
+#[cfg(target_arch = "wasm32")]
 mod callback_channel;
+#[cfg(target_arch = "wasm32")]
 mod deque_channel;
+#[cfg(target_arch = "wasm32")]
 mod webrtc;
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(not(target_arch = "wasm32"))]
+use native::{callback_channel, webrtc};
 mod signaling;
+pub mod config;
 pub mod util;
+#[cfg(target_arch = "wasm32")]
 pub mod client;
+#[cfg(any(target_arch = "wasm32", test))]
+mod client_pump;
 pub mod server;
+pub mod stats;
+pub mod simulator;
+mod send_queue;
+#[cfg(target_arch = "wasm32")]
 pub mod browser;
@@ -0,0 +1,503 @@
+use std::sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex};
+
+use bevy::log::warn;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use webrtc::{data_channel::{RTCDataChannel, data_channel_message::DataChannelMessage}, peer_connection::RTCPeerConnection};
+
+use crate::wasm_peers_rtc::{send_queue::SendQueue, util::is_unreliable_packet};
+
+use super::{deque_channel::{DequeChannel, Sender, Receiver}, runtime, Error};
+
+pub type Callback = Box<dyn FnMut() + Send + Sync>;
+pub type MessageCallback = Box<dyn FnMut(ChannelMessage) + Send + Sync>;
+
+pub trait CallbackChannel: Send + Sync {
+    fn clone(&self) -> Box<dyn CallbackChannel>;
+    fn set_onopen(&self, value: Option<Callback>);
+    fn set_onmessage(&self, value: Option<MessageCallback>);
+    fn set_onclose(&self, value: Option<Callback>);
+    fn set_onerror(&self, value: Option<Callback>);
+    fn send_with_str(&self, data: &str) -> Result<(), Error>;
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), Error>;
+    fn close(&self);
+    fn buffered_amount(&self) -> u32; // Bytes sent but not yet handed to the network
+}
+
+// Text messages carry JSON, binary messages carry raw packets
+#[derive(Clone)]
+pub enum ChannelMessage {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+pub struct SendRecvCallbackChannel {
+    channel: Box<dyn CallbackChannel>,
+    queue_sender: Sender<ChannelMessage>, // Enqueue newly received values
+    queue_receiver: Receiver<ChannelMessage>
+}
+
+impl Clone for SendRecvCallbackChannel {
+    fn clone(&self) -> Self {
+        Self { channel: self.channel.clone(), queue_sender: self.queue_sender.clone(), queue_receiver: self.queue_receiver.clone() }
+    }
+}
+
+// Wrapped, but maybe not open yet, see SendRecvCallbackChannel::wrap()
+pub struct OpeningChannel {
+    channel: SendRecvCallbackChannel,
+    open: oneshot::Receiver<bool>
+}
+
+impl OpeningChannel {
+    pub async fn opened(self) -> Result<SendRecvCallbackChannel, Error> {
+        if !self.open.await? {
+            return Err("Channel closed before opening".into());
+        }
+        Ok(self.channel)
+    }
+}
+
+impl SendRecvCallbackChannel {
+    pub async fn new(channel: Box<dyn CallbackChannel>) -> Result<SendRecvCallbackChannel, Error> {
+        Self::wrap(channel).opened().await
+    }
+
+    // Queues messages from here on, for channels whose messages may start arriving before we get to await them
+    pub fn wrap(channel: Box<dyn CallbackChannel>) -> OpeningChannel {
+        let (queue_sender, queue_receiver) = DequeChannel::channel();
+        let ws = SendRecvCallbackChannel {
+            channel,
+            queue_sender,
+            queue_receiver
+        };
+
+        // Resolved with true once open, or false if the channel dies first
+        let (open_sender, open_receiver) = oneshot::channel();
+        let open_sender = Arc::new(Mutex::new(Some(open_sender)));
+
+        let sender = ws.queue_sender.clone();
+        ws.channel.set_onmessage(Some(Box::new(move |data| {
+            sender.send(data).unwrap();
+        })));
+
+        let sender = ws.queue_sender.clone();
+        let open = open_sender.clone();
+        ws.channel.set_onerror(Some(Box::new(move || {
+            warn!("Error in channel!");
+            sender.close().unwrap();
+            if let Some(open) = open.lock().unwrap().take() {
+                let _ = open.send(false);
+            }
+        })));
+
+        let sender = ws.queue_sender.clone();
+        let open = open_sender.clone();
+        ws.channel.set_onclose(Some(Box::new(move || {
+            warn!("Closing channel.");
+            sender.close().unwrap();
+            if let Some(open) = open.lock().unwrap().take() {
+                let _ = open.send(false);
+            }
+        })));
+
+        ws.channel.set_onopen(Some(Box::new(move || {
+            if let Some(open) = open_sender.lock().unwrap().take() {
+                let _ = open.send(true);
+            }
+        })));
+
+        OpeningChannel { channel: ws, open: open_receiver }
+    }
+
+    pub fn send(&mut self, msg: impl Serialize + Sized) -> Result<(), Error> {
+        self.channel.send_with_str(&serde_json::to_string(&msg)?)?;
+        Ok(())
+    }
+
+    pub fn send_bytes(&self, data: &[u8]) -> Result<(), Error> {
+        self.channel.send_with_u8_array(data)
+    }
+
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<T, Error> {
+        match self.queue_receiver.recv().await? {
+            ChannelMessage::Text(value) => serde_json::from_str(&value).map_err(|e| {
+                format!("Failed to deserialize value `{}`: {}", value, e).into()
+            }),
+            ChannelMessage::Binary(_) => Err("Expected text message, got binary".into()),
+        }
+    }
+
+    pub fn drain_bytes(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        self.queue_receiver.drain()
+            .into_iter()
+            .map(|m| match m {
+                ChannelMessage::Binary(data) => Ok(data),
+                ChannelMessage::Text(_) => Err("Expected binary message, got text".into()),
+            })
+            .collect()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue_receiver.is_closed()
+    }
+
+    // onclose fires once the channel actually went down
+    pub fn close(&self) {
+        self.channel.close();
+    }
+
+    pub fn buffered_amount(&self) -> u32 {
+        self.channel.buffered_amount()
+    }
+}
+
+// The reliable/ordered and unreliable/unordered data channels of one peer.
+// renet packets are routed to the one matching the send type of their renet channel.
+#[derive(Clone)]
+pub struct DataChannels {
+    reliable: SendRecvCallbackChannel,
+    unreliable: SendRecvCallbackChannel,
+    queue: Arc<Mutex<SendQueue>>
+}
+
+impl DataChannels {
+    pub fn new(reliable: SendRecvCallbackChannel, unreliable: SendRecvCallbackChannel) -> DataChannels {
+        DataChannels { reliable, unreliable, queue: Arc::new(Mutex::new(SendQueue::default())) }
+    }
+
+    // Fails once the peer fell too far behind on reliable packets
+    pub fn send_packet(&mut self, packet: &[u8]) -> Result<(), Error> {
+        let mut queue = self.queue.lock().unwrap();
+        if is_unreliable_packet(packet) {
+            queue.send_unreliable(packet, self.unreliable.buffered_amount(), |packet| self.unreliable.send_bytes(packet))
+        } else {
+            queue.push_reliable(packet)?;
+            queue.flush(|| self.reliable.buffered_amount(), |packet| self.reliable.send_bytes(packet))
+        }
+    }
+
+    // Sends what congestion held back earlier, call once per frame
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.queue.lock().unwrap().flush(|| self.reliable.buffered_amount(), |packet| self.reliable.send_bytes(packet))
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.queue.lock().unwrap().queued_packets()
+    }
+
+    pub fn dropped_packets(&self) -> u64 {
+        self.queue.lock().unwrap().dropped_packets()
+    }
+
+    pub fn drain_packets(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut packets = self.reliable.drain_bytes()?;
+        packets.extend(self.unreliable.drain_bytes()?);
+        Ok(packets)
+    }
+
+    // Losing either channel makes the peer unusable
+    pub fn is_closed(&self) -> bool {
+        self.reliable.is_closed() || self.unreliable.is_closed()
+    }
+
+    pub fn close(&self) {
+        self.reliable.close();
+        self.unreliable.close();
+    }
+
+    pub fn buffered_amount(&self) -> u32 {
+        self.reliable.buffered_amount() + self.unreliable.buffered_amount()
+    }
+}
+
+#[derive(Default)]
+struct WebSocketHandlers {
+    opened: bool,
+    onopen: Option<Callback>,
+    onmessage: Option<MessageCallback>,
+    onclose: Option<Callback>,
+    onerror: Option<Callback>,
+}
+
+// Browser-like websocket: connects in the background and reports progress through callbacks
+#[derive(Clone)]
+pub struct WebSocket {
+    handlers: Arc<Mutex<WebSocketHandlers>>,
+    outgoing: mpsc::UnboundedSender<ChannelMessage>,
+    closing: Arc<Notify>
+}
+
+impl WebSocket {
+    pub fn new(url: &str) -> Result<WebSocket, Error> {
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded_channel::<ChannelMessage>();
+        let ws = WebSocket { handlers: Arc::new(Mutex::new(WebSocketHandlers::default())), outgoing, closing: Arc::new(Notify::new()) };
+        let handlers = ws.handlers.clone();
+        let closing = ws.closing.clone();
+        let url = url.to_owned();
+        runtime().spawn(async move {
+            let stream = match connect_async(url.as_str()).await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("WebSocket: failed to connect to {}: {}", url, e);
+                    Self::fire(&handlers, |h| &mut h.onerror);
+                    Self::fire(&handlers, |h| &mut h.onclose);
+                    return;
+                }
+            };
+            handlers.lock().unwrap().opened = true;
+            Self::fire(&handlers, |h| &mut h.onopen);
+
+            let (mut sink, mut stream) = stream.split();
+            runtime().spawn(async move {
+                loop {
+                    let data = tokio::select! {
+                        biased; // Whatever was sent before closing still goes out
+                        Some(data) = outgoing_receiver.recv() => data,
+                        _ = closing.notified() => break,
+                        else => break,
+                    };
+                    let msg = match data {
+                        ChannelMessage::Text(data) => Message::Text(data),
+                        ChannelMessage::Binary(data) => Message::Binary(data),
+                    };
+                    if sink.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                let _ = sink.close().await;
+            });
+
+            while let Some(msg) = stream.next().await {
+                let data = match msg {
+                    Ok(Message::Text(data)) => ChannelMessage::Text(data),
+                    Ok(Message::Binary(data)) => ChannelMessage::Binary(data),
+                    Ok(Message::Close(_)) => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("WebSocket: error reading from {}: {}", url, e);
+                        Self::fire(&handlers, |h| &mut h.onerror);
+                        break;
+                    }
+                };
+                if let Some(onmessage) = handlers.lock().unwrap().onmessage.as_mut() {
+                    onmessage(data);
+                }
+            }
+
+            Self::fire(&handlers, |h| &mut h.onclose);
+        });
+        Ok(ws)
+    }
+
+    fn fire(handlers: &Mutex<WebSocketHandlers>, handler: impl FnOnce(&mut WebSocketHandlers) -> &mut Option<Callback>) {
+        if let Some(f) = handler(&mut handlers.lock().unwrap()).as_mut() {
+            f();
+        }
+    }
+}
+
+impl CallbackChannel for WebSocket {
+    fn clone(&self) -> Box<dyn CallbackChannel> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn set_onopen(&self, value: Option<Callback>) {
+        let mut handlers = self.handlers.lock().unwrap();
+        match value {
+            // Already connected, fire immediately like the browser would have
+            Some(mut f) if handlers.opened => f(),
+            value => handlers.onopen = value,
+        }
+    }
+
+    fn set_onmessage(&self, value: Option<MessageCallback>) {
+        self.handlers.lock().unwrap().onmessage = value;
+    }
+
+    fn set_onclose(&self, value: Option<Callback>) {
+        self.handlers.lock().unwrap().onclose = value;
+    }
+
+    fn set_onerror(&self, value: Option<Callback>) {
+        self.handlers.lock().unwrap().onerror = value;
+    }
+
+    fn send_with_str(&self, data: &str) -> Result<(), Error> {
+        self.outgoing.send(ChannelMessage::Text(data.to_owned())).map_err(|_| "WebSocket is closed".into())
+    }
+
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), Error> {
+        self.outgoing.send(ChannelMessage::Binary(data.to_vec())).map_err(|_| "WebSocket is closed".into())
+    }
+
+    // Sends a close frame; onclose fires once the server acknowledged it
+    fn close(&self) {
+        self.closing.notify_one();
+    }
+
+    // Not tracked, nothing looks at the signaling connection's throughput
+    fn buffered_amount(&self) -> u32 {
+        0
+    }
+}
+
+// Wraps an RTCDataChannel with a synchronous, order-preserving send
+#[derive(Clone)]
+pub struct DataChannel {
+    channel: Arc<RTCDataChannel>,
+    outgoing: mpsc::UnboundedSender<ChannelMessage>,
+    queued: Arc<AtomicU32>, // Bytes waiting for the send task
+    buffered: Arc<AtomicU32> // The channel's own buffer, as of the last send
+}
+
+impl DataChannel {
+    pub fn new(channel: Arc<RTCDataChannel>, connection: Arc<RTCPeerConnection>) -> DataChannel {
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded_channel::<ChannelMessage>();
+        let (queued, buffered) = (Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)));
+        let channel_cloned = channel.clone();
+        let (queued_cloned, buffered_cloned) = (queued.clone(), buffered.clone());
+        runtime().spawn(async move {
+            while let Some(data) = outgoing_receiver.recv().await {
+                let len = match &data {
+                    ChannelMessage::Text(data) => data.len(),
+                    ChannelMessage::Binary(data) => data.len(),
+                };
+                let sent = match data {
+                    ChannelMessage::Text(data) => channel_cloned.send_text(data).await,
+                    ChannelMessage::Binary(data) => channel_cloned.send(&Bytes::from(data)).await,
+                };
+                queued_cloned.fetch_sub(len as u32, Ordering::Relaxed);
+                buffered_cloned.store(channel_cloned.buffered_amount().await as u32, Ordering::Relaxed);
+                if let Err(e) = sent {
+                    warn!("DataChannel: failed to send: {}", e);
+                    break;
+                }
+            }
+            // Every handle to this channel is gone (or broken), so nobody needs the peer connection anymore
+            let _ = connection.close().await;
+        });
+        DataChannel { channel, outgoing, queued, buffered }
+    }
+
+    fn enqueue(&self, data: ChannelMessage, len: usize) -> Result<(), Error> {
+        self.queued.fetch_add(len as u32, Ordering::Relaxed);
+        self.outgoing.send(data).map_err(|_| {
+            self.queued.fetch_sub(len as u32, Ordering::Relaxed);
+            "DataChannel is closed".into()
+        })
+    }
+}
+
+impl CallbackChannel for DataChannel {
+    fn clone(&self) -> Box<dyn CallbackChannel> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn set_onopen(&self, value: Option<Callback>) {
+        if let Some(mut f) = value {
+            self.channel.on_open(Box::new(move || {
+                f();
+                Box::pin(async {})
+            }));
+        }
+    }
+
+    fn set_onmessage(&self, value: Option<MessageCallback>) {
+        if let Some(mut f) = value {
+            self.channel.on_message(Box::new(move |msg: DataChannelMessage| {
+                if !msg.is_string {
+                    f(ChannelMessage::Binary(msg.data.to_vec()));
+                } else {
+                    match String::from_utf8(msg.data.to_vec()) {
+                        Ok(data) => f(ChannelMessage::Text(data)),
+                        Err(e) => warn!("DataChannel: dropping invalid text message: {}", e),
+                    }
+                }
+                Box::pin(async {})
+            }));
+        }
+    }
+
+    fn set_onclose(&self, value: Option<Callback>) {
+        if let Some(mut f) = value {
+            self.channel.on_close(Box::new(move || {
+                f();
+                Box::pin(async {})
+            }));
+        }
+    }
+
+    fn set_onerror(&self, value: Option<Callback>) {
+        if let Some(mut f) = value {
+            self.channel.on_error(Box::new(move |e| {
+                warn!("DataChannel: {}", e);
+                f();
+                Box::pin(async {})
+            }));
+        }
+    }
+
+    fn send_with_str(&self, data: &str) -> Result<(), Error> {
+        self.enqueue(ChannelMessage::Text(data.to_owned()), data.len())
+    }
+
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), Error> {
+        self.enqueue(ChannelMessage::Binary(data.to_vec()), data.len())
+    }
+
+    fn close(&self) {
+        let channel = self.channel.clone();
+        runtime().spawn(async move {
+            if let Err(e) = channel.close().await {
+                warn!("DataChannel: failed to close: {}", e);
+            }
+        });
+    }
+
+    fn buffered_amount(&self) -> u32 {
+        self.queued.load(Ordering::Relaxed) + self.buffered.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::loopback::{block_on, LoopbackChannel}, *};
+
+    #[test]
+    fn keeps_messages_arriving_before_opened_is_awaited() {
+        block_on(async {
+            let (ours, theirs) = LoopbackChannel::pair();
+            let opening = SendRecvCallbackChannel::wrap(CallbackChannel::clone(&ours));
+            ours.open();
+            theirs.send_with_str("\"first\"").unwrap();
+            let mut channel = opening.opened().await.unwrap();
+            assert_eq!(channel.recv::<String>().await.unwrap(), "first");
+        });
+    }
+
+    #[test]
+    fn fails_to_open_after_an_error() {
+        block_on(async {
+            let (ours, _theirs) = LoopbackChannel::pair();
+            let opening = SendRecvCallbackChannel::wrap(CallbackChannel::clone(&ours));
+            ours.error();
+            assert!(opening.opened().await.is_err());
+        });
+    }
+
+    #[test]
+    fn an_error_ends_recv() {
+        block_on(async {
+            let (ours, _theirs) = LoopbackChannel::pair();
+            ours.open();
+            let mut channel = SendRecvCallbackChannel::new(CallbackChannel::clone(&ours)).await.unwrap();
+            ours.error();
+            assert!(channel.recv::<String>().await.is_err());
+            assert!(channel.is_closed());
+        });
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use tokio::sync::Notify;
+
+use super::Error;
+
+#[derive(Clone)]
+pub struct Sender<T> {
+    channel: Arc<DequeChannel<T>>
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, data: T) -> Result<(), Error> {
+        self.channel.state.lock().unwrap().buffer.push_back(data);
+        self.channel.notify.notify_waiters();
+        Ok(())
+    }
+
+    pub fn close(&self) -> Result<(), Error> {
+        self.channel.state.lock().unwrap().closed = true;
+        self.channel.notify.notify_waiters();
+        Ok(())
+    }
+
+    pub fn same_channel(&self, other: &Sender<T>) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+}
+
+#[derive(Clone)]
+pub struct Receiver<T> {
+    channel: Arc<DequeChannel<T>>
+}
+
+impl<T> Receiver<T> {
+    pub async fn recv(&self) -> Result<T, Error> {
+        loop {
+            // Register for wakeups before checking, so a send in between is not missed
+            let notified = self.channel.notify.notified();
+            {
+                let mut state = self.channel.state.lock().unwrap();
+                if let Some(data) = state.buffer.pop_front() {
+                    return Ok(data);
+                }
+                if state.closed {
+                    return Err("Empty channel!".into());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    pub fn drain(&self) -> Vec<T> {
+        self.channel.state.lock().unwrap().buffer.drain(..).collect::<Vec<_>>()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.channel.state.lock().unwrap().closed
+    }
+}
+
+struct DequeState<T> {
+    closed: bool,
+    buffer: VecDeque<T>,
+}
+
+pub struct DequeChannel<T> {
+    state: Mutex<DequeState<T>>,
+    notify: Notify
+}
+
+impl<T> DequeChannel<T> {
+    pub fn channel() -> (Sender<T>, Receiver<T>) {
+        let channel = Arc::new(DequeChannel {
+            state: Mutex::new(DequeState {
+                closed: false,
+                buffer: VecDeque::new(),
+            }),
+            notify: Notify::new()
+        });
+        (Sender { channel: channel.clone() }, Receiver { channel })
+    }
+}
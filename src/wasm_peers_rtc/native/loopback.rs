@@ -0,0 +1,218 @@
+// In-process CallbackChannel pair, so the transport can be driven deterministically without sockets.
+// Nothing happens on its own: the owner decides when each end opens, closes or errors.
+use std::{collections::VecDeque, future::Future, mem, sync::{Arc, Mutex}};
+
+use crate::wasm_peers_rtc::{config::NetworkConfig, signaling::{RelayMessage, SignalingMessage}, util::timeout};
+
+use super::{callback_channel::{Callback, CallbackChannel, ChannelMessage, DataChannels, MessageCallback, SendRecvCallbackChannel}, runtime, webrtc::AsyncWebRtcServer, Error};
+
+#[derive(Default)]
+struct Endpoint {
+    open: bool,
+    closed: bool,
+    onopen: Option<Callback>,
+    onmessage: Option<MessageCallback>,
+    onclose: Option<Callback>,
+    onerror: Option<Callback>,
+    pending: VecDeque<ChannelMessage> // Delivered before anyone listened
+}
+
+#[derive(Clone)]
+pub struct LoopbackChannel {
+    local: Arc<Mutex<Endpoint>>,
+    remote: Arc<Mutex<Endpoint>>
+}
+
+impl LoopbackChannel {
+    pub fn pair() -> (LoopbackChannel, LoopbackChannel) {
+        let a = Arc::new(Mutex::new(Endpoint::default()));
+        let b = Arc::new(Mutex::new(Endpoint::default()));
+        (
+            LoopbackChannel { local: a.clone(), remote: b.clone() },
+            LoopbackChannel { local: b, remote: a }
+        )
+    }
+
+    // Open both ends, firing onopen on each
+    pub fn open(&self) {
+        for endpoint in [&self.local, &self.remote] {
+            let already_open = mem::replace(&mut endpoint.lock().unwrap().open, true);
+            if !already_open {
+                Self::fire(endpoint, |e| &mut e.onopen);
+            }
+        }
+    }
+
+    // Close both ends, firing onclose on each
+    pub fn close(&self) {
+        for endpoint in [&self.local, &self.remote] {
+            let already_closed = mem::replace(&mut endpoint.lock().unwrap().closed, true);
+            if !already_closed {
+                Self::fire(endpoint, |e| &mut e.onclose);
+            }
+        }
+    }
+
+    // Fire onerror on this end only, leaving the channel otherwise untouched
+    pub fn error(&self) {
+        Self::fire(&self.local, |e| &mut e.onerror);
+    }
+
+    // Callbacks run without the lock held, so they may freely use the channel again
+    fn fire(endpoint: &Mutex<Endpoint>, handler: impl Fn(&mut Endpoint) -> &mut Option<Callback>) {
+        let f = handler(&mut endpoint.lock().unwrap()).take();
+        if let Some(mut f) = f {
+            f();
+            let mut endpoint = endpoint.lock().unwrap();
+            handler(&mut endpoint).get_or_insert(f);
+        }
+    }
+
+    fn send(&self, data: ChannelMessage) -> Result<(), Error> {
+        {
+            let local = self.local.lock().unwrap();
+            if !local.open || local.closed {
+                return Err("LoopbackChannel is not open".into());
+            }
+        }
+        self.remote.lock().unwrap().pending.push_back(data);
+        Self::deliver(&self.remote);
+        Ok(())
+    }
+
+    fn deliver(endpoint: &Mutex<Endpoint>) {
+        // Loop, since more data may arrive while the callback runs
+        loop {
+            let (mut f, pending) = {
+                let mut endpoint = endpoint.lock().unwrap();
+                if endpoint.pending.is_empty() {
+                    return;
+                }
+                match endpoint.onmessage.take() {
+                    Some(f) => (f, mem::take(&mut endpoint.pending)),
+                    None => return,
+                }
+            };
+            for data in pending {
+                f(data);
+            }
+            endpoint.lock().unwrap().onmessage.get_or_insert(f);
+        }
+    }
+}
+
+impl CallbackChannel for LoopbackChannel {
+    fn clone(&self) -> Box<dyn CallbackChannel> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn set_onopen(&self, value: Option<Callback>) {
+        let open = {
+            let mut local = self.local.lock().unwrap();
+            local.onopen = value;
+            local.open && !local.closed
+        };
+        if open {
+            Self::fire(&self.local, |e| &mut e.onopen);
+        }
+    }
+
+    fn set_onmessage(&self, value: Option<MessageCallback>) {
+        self.local.lock().unwrap().onmessage = value;
+        Self::deliver(&self.local);
+    }
+
+    fn set_onclose(&self, value: Option<Callback>) {
+        self.local.lock().unwrap().onclose = value;
+    }
+
+    fn set_onerror(&self, value: Option<Callback>) {
+        self.local.lock().unwrap().onerror = value;
+    }
+
+    fn send_with_str(&self, data: &str) -> Result<(), Error> {
+        self.send(ChannelMessage::Text(data.to_owned()))
+    }
+
+    fn send_with_u8_array(&self, data: &[u8]) -> Result<(), Error> {
+        self.send(ChannelMessage::Binary(data.to_vec()))
+    }
+
+    fn close(&self) {
+        LoopbackChannel::close(self);
+    }
+
+    // Delivered on send, so nothing ever waits
+    fn buffered_amount(&self) -> u32 {
+        0
+    }
+}
+
+// Runs a test on the transport's runtime, failing it rather than hanging if something never arrives
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(timeout(5000, "the test", future)).unwrap()
+}
+
+// The signaling server's end of a loopback signaling channel, speaking its wire format
+pub struct LoopbackHub {
+    channel: LoopbackChannel,
+    received: SendRecvCallbackChannel
+}
+
+impl LoopbackHub {
+    // An open signaling channel to hand to the code under test, and the hub at its other end
+    pub async fn pair() -> (LoopbackChannel, LoopbackHub) {
+        let (ours, hub) = LoopbackChannel::pair();
+        ours.open();
+        let received = SendRecvCallbackChannel::new(CallbackChannel::clone(&hub)).await.unwrap();
+        (ours, LoopbackHub { channel: hub, received })
+    }
+
+    pub fn send(&self, msg: SignalingMessage) {
+        self.send_raw(&serde_json::to_string(&msg).unwrap());
+    }
+
+    // Relays carry their source, which our own Relay messages never serialize
+    pub fn relay(&self, src: &str, data: RelayMessage) {
+        let mut msg = serde_json::to_value(SignalingMessage::Relay { src: "".to_owned(), dst: "".to_owned(), data }).unwrap();
+        msg["src"] = src.into();
+        self.send_raw(&msg.to_string());
+    }
+
+    pub fn send_raw(&self, data: &str) {
+        self.channel.send_with_str(data).unwrap();
+    }
+
+    // Parsed loosely, since what we send the hub doesn't parse back into a SignalingMessage
+    pub async fn recv(&mut self) -> Result<serde_json::Value, Error> {
+        self.received.recv().await
+    }
+
+    pub fn close(&self) {
+        self.channel.close();
+    }
+}
+
+// A server registered over loopback. Re-registering goes to config.signaling_url
+pub async fn registered_server(config: &NetworkConfig) -> (AsyncWebRtcServer, LoopbackHub) {
+    let (signaling, mut hub) = LoopbackHub::pair().await;
+    let (server, registered) = tokio::join!(AsyncWebRtcServer::with_signaling(Box::new(signaling), config), async {
+        let registered = hub.recv().await.unwrap();
+        hub.send(SignalingMessage::List { servers: Default::default() });
+        registered
+    });
+    assert_eq!(registered["action"], "register");
+    (server.unwrap(), hub)
+}
+
+// Both ends of an open peer connection's data channels
+pub async fn data_channels_pair() -> (DataChannels, DataChannels) {
+    let mut ends = Vec::new();
+    for _ in 0..2 {
+        let (a, b) = LoopbackChannel::pair();
+        a.open();
+        ends.push((SendRecvCallbackChannel::new(Box::new(a)).await.unwrap(), SendRecvCallbackChannel::new(Box::new(b)).await.unwrap()));
+    }
+    let (unreliable, reliable) = (ends.pop().unwrap(), ends.pop().unwrap());
+    (DataChannels::new(reliable.0, unreliable.0), DataChannels::new(reliable.1, unreliable.1))
+}
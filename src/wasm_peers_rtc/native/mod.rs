@@ -0,0 +1,26 @@
+// Native (non-wasm) counterparts of the browser transport, built on tokio, tungstenite and webrtc-rs.
+// Mirrors the module layout of the wasm side so the bevy plugins can use either interchangeably.
+pub mod callback_channel;
+pub mod deque_channel;
+#[cfg(test)]
+pub mod loopback;
+pub mod signaling;
+pub mod webrtc;
+
+use std::sync::OnceLock;
+
+use tokio::runtime::{Builder, Runtime};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+pub fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("wasm-peers-rtc")
+            .enable_all()
+            .build()
+            .expect("Failed to start tokio runtime")
+    })
+}
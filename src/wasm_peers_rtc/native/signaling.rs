@@ -0,0 +1,236 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use bevy::log::warn;
+
+use crate::wasm_peers_rtc::signaling::{ConnectionId, RelayMessage, SignalingMessage};
+
+use super::{callback_channel::SendRecvCallbackChannel, deque_channel::{DequeChannel, Receiver, Sender}, Error};
+
+type DemuxClients = Arc<Mutex<HashMap<ConnectionId, Sender<RelayMessage>>>>;
+
+pub struct SignalingDemux {
+    signaling_server: SendRecvCallbackChannel,
+    clients: DemuxClients // Entries remove themselves once their SignalingClientConnection is dropped
+}
+
+pub enum SignalingDemuxRecv {
+    System(SignalingMessage),
+    Relay(ConnectionId, SignalingClientConnection)
+}
+
+impl SignalingDemux {
+    pub fn new(signaling_server: SendRecvCallbackChannel) -> SignalingDemux {
+        SignalingDemux { signaling_server, clients: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub async fn recv(&mut self) -> Result<SignalingDemuxRecv, Error> {
+        loop {
+            let msg: SignalingMessage = match self.signaling_server.recv().await {
+                Ok(msg) => msg,
+                // A peer relaying garbage mustn't cost us the signaling server
+                Err(e) if !self.signaling_server.is_closed() => {
+                    warn!("SignalingDemux: ignoring malformed message: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match msg {
+                SignalingMessage::Relay { src, dst: _dst, data } => {
+                    let existing = self.clients.lock().unwrap().get(&src).cloned();
+                    if let Some(sender) = existing {
+                        sender.send(data)?;
+                        continue;
+                    }
+                    // New connection
+                    let (sender, receiver) = DequeChannel::<RelayMessage>::channel();
+                    sender.send(data)?;
+                    self.clients.lock().unwrap().insert(src.to_owned(), sender.clone());
+                    return Ok(SignalingDemuxRecv::Relay(src.clone(), SignalingClientConnection {
+                        connection_id: src,
+                        signaling_server: self.signaling_server.clone(),
+                        sender,
+                        receiver,
+                        clients: self.clients.clone()
+                    }));
+                }
+                _ => {
+                    // System message
+                    return Ok(SignalingDemuxRecv::System(msg))
+                }
+            }
+        }
+    }
+}
+
+// Without the signaling server, nobody will relay anything to the connections anymore
+impl Drop for SignalingDemux {
+    fn drop(&mut self) {
+        for sender in self.clients.lock().unwrap().values() {
+            let _ = sender.close();
+        }
+    }
+}
+
+pub struct SignalingClientConnection {
+    connection_id: String,
+    signaling_server: SendRecvCallbackChannel, // send-only
+    sender: Sender<RelayMessage>, // The demux's end, to close it
+    receiver: Receiver<RelayMessage>,
+    clients: DemuxClients
+}
+
+impl Drop for SignalingClientConnection {
+    fn drop(&mut self) {
+        let mut clients = self.clients.lock().unwrap();
+        // A newer connection from the same peer may have taken over the entry already
+        if clients.get(&self.connection_id).is_some_and(|sender| sender.same_channel(&self.sender)) {
+            clients.remove(&self.connection_id);
+        }
+    }
+}
+
+impl SignalingClientConnection {
+    pub fn send(&mut self, msg: RelayMessage) -> Result<(), Error> {
+        self.signaling_server.send(SignalingMessage::Relay {
+            src: "".to_owned(),
+            dst: self.connection_id.to_owned(),
+            data: msg
+        })
+    }
+
+    pub async fn recv(&mut self) -> Result<RelayMessage, Error> {
+        self.receiver.recv().await
+    }
+
+    pub fn clone_sender(&self) -> SignalingClientSender {
+        SignalingClientSender {
+            connection_id: self.connection_id.clone(),
+            signaling_server: self.signaling_server.clone()
+        }
+    }
+
+    // Lets whoever gave up on the connection end recv(), even while another task is blocked in it
+    pub fn closer(&self) -> SignalingClientCloser {
+        SignalingClientCloser { sender: self.sender.clone() }
+    }
+}
+
+pub struct SignalingClientCloser {
+    sender: Sender<RelayMessage>
+}
+
+impl SignalingClientCloser {
+    pub fn close(&self) {
+        let _ = self.sender.close();
+    }
+}
+
+pub struct SignalingClientSender {
+    connection_id: String,
+    signaling_server: SendRecvCallbackChannel, // send-only
+}
+
+impl SignalingClientSender {
+    pub fn send(&mut self, msg: RelayMessage) -> Result<(), Error> {
+        self.signaling_server.send(SignalingMessage::Relay {
+            src: "".to_owned(),
+            dst: self.connection_id.to_owned(),
+            data: msg
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::loopback::{block_on, LoopbackHub}, *};
+
+    async fn demux() -> (SignalingDemux, LoopbackHub) {
+        let (signaling, hub) = LoopbackHub::pair().await;
+        (SignalingDemux::new(SendRecvCallbackChannel::new(Box::new(signaling)).await.unwrap()), hub)
+    }
+
+    fn offer(sdp: &str) -> RelayMessage {
+        RelayMessage::Offer(sdp.to_owned())
+    }
+
+    async fn next_connection(demux: &mut SignalingDemux) -> (ConnectionId, SignalingClientConnection) {
+        match demux.recv().await.unwrap() {
+            SignalingDemuxRecv::Relay(connection_id, connection) => (connection_id, connection),
+            SignalingDemuxRecv::System(msg) => panic!("Expected a relay, got {:?}", msg),
+        }
+    }
+
+    async fn next_offer(connection: &mut SignalingClientConnection) -> String {
+        match connection.recv().await.unwrap() {
+            RelayMessage::Offer(sdp) => sdp,
+            msg => panic!("Expected an offer, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn routes_relays_by_source() {
+        block_on(async {
+            let (mut demux, mut hub) = demux().await;
+            hub.relay("a", offer("a1"));
+            let (connection_id, mut a) = next_connection(&mut demux).await;
+            assert_eq!(connection_id, "a");
+            assert_eq!(next_offer(&mut a).await, "a1");
+
+            // Only new sources surface from the demux, the rest go to their connection
+            hub.relay("a", offer("a2"));
+            hub.relay("b", offer("b1"));
+            hub.send(SignalingMessage::List { servers: HashMap::new() });
+            let (connection_id, mut b) = next_connection(&mut demux).await;
+            assert_eq!(connection_id, "b");
+            assert!(matches!(demux.recv().await.unwrap(), SignalingDemuxRecv::System(SignalingMessage::List { .. })));
+            assert_eq!(next_offer(&mut a).await, "a2");
+            assert_eq!(next_offer(&mut b).await, "b1");
+
+            // Replies are addressed to the connection's peer
+            a.send(RelayMessage::Answer("answer".to_owned())).unwrap();
+            let reply = hub.recv().await.unwrap();
+            assert_eq!(reply["action"], "relay");
+            assert_eq!(reply["dst"], "a");
+            assert_eq!(reply["data"]["type"], "Answer");
+        });
+    }
+
+    #[test]
+    fn skips_malformed_messages() {
+        block_on(async {
+            let (mut demux, hub) = demux().await;
+            hub.send_raw("not json");
+            hub.send_raw(r#"{"action":"relay","src":"a"}"#);
+            hub.relay("a", offer("a1"));
+            let (connection_id, _) = next_connection(&mut demux).await;
+            assert_eq!(connection_id, "a");
+        });
+    }
+
+    #[test]
+    fn dropped_connection_makes_room_for_a_new_one() {
+        block_on(async {
+            let (mut demux, hub) = demux().await;
+            hub.relay("a", offer("a1"));
+            let (_, a) = next_connection(&mut demux).await;
+            drop(a);
+            hub.relay("a", offer("a2"));
+            let (_, mut a) = next_connection(&mut demux).await;
+            assert_eq!(next_offer(&mut a).await, "a2");
+        });
+    }
+
+    #[test]
+    fn losing_the_hub_ends_every_connection() {
+        block_on(async {
+            let (mut demux, hub) = demux().await;
+            hub.relay("a", offer("a1"));
+            let (_, mut a) = next_connection(&mut demux).await;
+            assert_eq!(next_offer(&mut a).await, "a1");
+            hub.close();
+            assert!(demux.recv().await.is_err());
+            drop(demux);
+            assert!(a.recv().await.is_err());
+        });
+    }
+}
@@ -0,0 +1,454 @@
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
+
+use bevy::log::{info, warn};
+use tokio::sync::mpsc;
+use webrtc::{
+    api::APIBuilder,
+    ice_transport::{ice_candidate::{RTCIceCandidate, RTCIceCandidateInit}, ice_connection_state::RTCIceConnectionState, ice_server::RTCIceServer},
+    peer_connection::{configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription, RTCPeerConnection}
+};
+
+use crate::wasm_peers_rtc::{config::{IceServer, NetworkConfig, PROTOCOL_VERSION}, signaling::{ConnectionId, HandshakeMessage, ServerEntry, SignalingMessage, RelayMessage}, util::{sleep, timeout, Backoff, HANDSHAKE_LINGER_MS, RELIABLE_CHANNEL_LABEL, UNRELIABLE_CHANNEL_LABEL}};
+
+use super::{callback_channel::{CallbackChannel, SendRecvCallbackChannel, WebSocket, DataChannel, DataChannels}, signaling::{SignalingDemux, SignalingDemuxRecv, SignalingClientConnection}, runtime, Error};
+
+async fn make_rtc_peer(ice_servers: &[IceServer]) -> Result<RTCPeerConnection, Error> {
+    let config = RTCConfiguration {
+        ice_servers: ice_servers.iter().map(|server| RTCIceServer {
+            urls: vec![server.url.to_owned()],
+            username: server.username.to_owned().unwrap_or_default(),
+            credential: server.credential.to_owned().unwrap_or_default(),
+            ..Default::default()
+        }).collect(),
+        ..Default::default()
+    };
+
+    Ok(APIBuilder::new().build().new_peer_connection(config).await?)
+}
+
+#[derive(Clone)]
+pub struct AsyncWebRtcServer {
+    config: Arc<NetworkConfig>,
+    entry: Arc<Mutex<ServerEntry>>,
+    signaling: Arc<Mutex<Option<SendRecvCallbackChannel>>>, // None while re-registering after losing the signaling server
+    clients: Arc<Mutex<HashMap<ConnectionId, DataChannels>>>,
+    new_clients: Arc<Mutex<VecDeque<ConnectionId>>>,
+    closed: Arc<AtomicBool> // Unregistered for good, don't re-register
+}
+
+impl AsyncWebRtcServer {
+    pub async fn new(config: &NetworkConfig) -> Result<AsyncWebRtcServer, Error> {
+        let websocket = WebSocket::new(&config.signaling_url)?;
+        Self::with_signaling(Box::new(websocket), config).await
+    }
+
+    // Register as a server over an already-created signaling channel
+    pub async fn with_signaling(signaling: Box<dyn CallbackChannel>, config: &NetworkConfig) -> Result<AsyncWebRtcServer, Error> {
+        let entry = config.server_entry();
+        let ws = Self::register(signaling, &entry).await?;
+
+        let server = AsyncWebRtcServer {
+            config: Arc::new(config.clone()),
+            entry: Arc::new(Mutex::new(entry)),
+            signaling: Arc::new(Mutex::new(Some(ws.clone()))),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            new_clients: Arc::new(Mutex::new(VecDeque::new())),
+            closed: Arc::new(AtomicBool::new(false))
+        };
+
+        runtime().spawn(Self::listen(server.clone(), ws));
+
+        Ok(server)
+    }
+
+    async fn register(signaling: Box<dyn CallbackChannel>, entry: &ServerEntry) -> Result<SendRecvCallbackChannel, Error> {
+        let mut ws = SendRecvCallbackChannel::new(signaling).await?;
+        ws.send(SignalingMessage::Register(entry.clone()))?;
+
+        // Discard list of existing servers
+        let _: SignalingMessage = ws.recv().await?;
+
+        Ok(ws)
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.signaling.lock().unwrap().is_some()
+    }
+
+    // Change what the server browser shows about us, re-registering if anything changed
+    pub fn update_entry(&self, update: impl FnOnce(&mut ServerEntry)) {
+        let mut entry = self.entry.lock().unwrap();
+        let previous = entry.clone();
+        update(&mut entry);
+        if *entry == previous {
+            return;
+        }
+        // While re-registering, the new entry is sent once we're back
+        if let Some(signaling) = self.signaling.lock().unwrap().as_mut() {
+            if let Err(e) = signaling.send(SignalingMessage::Register(entry.clone())) {
+                warn!("WebRtcServer: Failed to update server entry: {}", e);
+            }
+        }
+    }
+
+    // Leave the server list, existing clients stay connected
+    pub fn unregister(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(mut signaling) = self.signaling.lock().unwrap().take() {
+            if let Err(e) = signaling.send(SignalingMessage::Unregister) {
+                warn!("WebRtcServer: Failed to unregister: {}", e);
+            }
+            signaling.close();
+        }
+    }
+
+    pub fn close(&self) {
+        self.unregister();
+        for (_, channels) in self.clients.lock().unwrap().drain() {
+            channels.close();
+        }
+    }
+
+    pub fn clients(&self) -> HashMap<ConnectionId, DataChannels> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    pub fn add_client(&self, connection_id: ConnectionId, channels: DataChannels) {
+        self.clients.lock().unwrap().insert(connection_id.clone(), channels);
+        self.new_clients.lock().unwrap().push_back(connection_id);
+    }
+
+    pub fn remove_client(&self, connection: &str) {
+        self.clients.lock().unwrap().remove(connection);
+    }
+
+    pub fn new_clients(&self) -> Vec<ConnectionId> {
+        self.new_clients.lock().unwrap().drain(..).collect()
+    }
+
+    // Accept connections until the signaling server goes away, then re-register and repeat.
+    // Established clients don't need signaling anymore, so they stay connected meanwhile.
+    async fn listen(server: Self, mut signaling: SendRecvCallbackChannel) {
+        loop {
+            Self::accept_connections(&server, signaling).await;
+            *server.signaling.lock().unwrap() = None;
+
+            let mut backoff = Backoff::default();
+            signaling = loop {
+                if server.closed.load(Ordering::Relaxed) {
+                    return;
+                }
+                backoff.wait().await;
+                info!("WebRtcServer: Re-registering with {}", server.config.signaling_url);
+                let entry = server.entry.lock().unwrap().clone();
+                let registered = match WebSocket::new(&server.config.signaling_url) {
+                    Ok(websocket) => Self::register(Box::new(websocket), &entry).await,
+                    Err(e) => Err(e),
+                };
+                match registered {
+                    Ok(ws) => break ws,
+                    Err(e) => warn!("WebRtcServer: Failed to re-register: {}", e),
+                }
+            };
+            if server.closed.load(Ordering::Relaxed) {
+                signaling.close();
+                return;
+            }
+            *server.signaling.lock().unwrap() = Some(signaling.clone());
+            info!("WebRtcServer: Re-registered");
+        }
+    }
+
+    async fn accept_connections(server: &Self, signaling: SendRecvCallbackChannel) {
+        let mut demux = SignalingDemux::new(signaling);
+        loop {
+            match demux.recv().await {
+                Ok(SignalingDemuxRecv::System(msg)) => {
+                    warn!("ERROR: WebRtcServer.listen(): Unexpected system message: {:?}", msg);
+                },
+                Ok(SignalingDemuxRecv::Relay(connection_id, client_conn)) => {
+                    info!("WebRtcServer: Handling new connection...");
+                    let server = server.clone();
+                    runtime().spawn(async move {
+                        match Self::handle_connection(client_conn, &server.config).await {
+                            Ok(channels) => {
+                                server.add_client(connection_id.clone(), channels);
+                                info!("WebRtcServer: Added connection {}", connection_id);
+                            }
+                            Err(e) => warn!("WebRtcServer: Failed to establish connection {}: {}", connection_id, e),
+                        }
+                    });
+                }
+                Err(_) if server.closed.load(Ordering::Relaxed) => return,
+                Err(e) => {
+                    warn!("ERROR: WebRtcServer.listen(): {:?}. Lost signaling server", e);
+                    return;
+                },
+            }
+        }
+    }
+
+    // Negotiate with a new client, giving up on it if any step stalls
+    async fn handle_connection(client_conn: SignalingClientConnection, config: &NetworkConfig) -> Result<DataChannels, Error> {
+        info!("\t\tWebRtcServer.handle_connection(): Started");
+        let closer = client_conn.closer();
+        let peer = Arc::new(make_rtc_peer(&config.ice_servers).await?);
+        let result = Self::negotiate(client_conn, peer.clone(), config.negotiation_timeout_ms, &config.password).await;
+        if result.is_ok() {
+            Self::monitor_ice(&peer, config.ice_restart_timeout_ms);
+        } else {
+            // Ends the ICE candidate task, whose connection then leaves the demux
+            closer.close();
+            let _ = peer.close().await;
+        }
+        result
+    }
+
+    async fn negotiate(mut client_conn: SignalingClientConnection, peer: Arc<RTCPeerConnection>, timeout_ms: u32, password: &Option<String>) -> Result<DataChannels, Error> {
+        // Receive OFFER from client
+        let msg = timeout(timeout_ms, "offer", client_conn.recv()).await??;
+        let offer_sdp = if let RelayMessage::Offer(offer_sdp) = msg {
+            info!("\t\tWebRtcServer.handle_connection(): Received OFFER");
+            offer_sdp
+        } else {
+            return Err(format!("WebRtcServer.handle_connection(): Unexpected msg from signaling server: {:?}", msg).into());
+        };
+
+        // Get data channels, once the client opens them. They're wrapped right away: webrtc-rs starts reading
+        // as soon as this handler returns, and drops whatever arrives before there's a message handler
+        let (data_channel_sender, mut data_channel_receiver) = mpsc::unbounded_channel();
+        let weak_peer = Arc::downgrade(&peer); // The peer owns this handler
+        peer.on_data_channel(Box::new(move |channel| {
+            let label = channel.label().to_owned();
+            match (label.as_str(), weak_peer.upgrade()) {
+                (RELIABLE_CHANNEL_LABEL | UNRELIABLE_CHANNEL_LABEL, Some(peer)) => {
+                    let channel = SendRecvCallbackChannel::wrap(Box::new(DataChannel::new(channel, peer)));
+                    let _ = data_channel_sender.send((label, channel));
+                }
+                (RELIABLE_CHANNEL_LABEL | UNRELIABLE_CHANNEL_LABEL, None) => {}
+                _ => warn!("WebRtcServer.handle_connection(): Ignoring unexpected data channel `{}`", label),
+            }
+            Box::pin(async {})
+        }));
+
+        peer.set_remote_description(RTCSessionDescription::offer(offer_sdp)?).await?;
+
+        // Send ICE candidates to client
+        let mut client_sender = client_conn.clone_sender();
+        peer.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            if let Some(candidate) = candidate.and_then(|c| c.to_json().ok()) {
+                client_sender.send(RelayMessage::IceCandidate {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid.filter(|mid| !mid.is_empty()),
+                    sdp_m_line_index: candidate.sdp_mline_index
+                }).unwrap_or_else(|e| warn!("WebRtcServer.handle_connection(): Failed to send ICE candidate: {}", e));
+            }
+            Box::pin(async {})
+        }));
+
+        // Send ANSWER to client
+        let answer = peer.create_answer(None).await?;
+        let answer_sdp = answer.sdp.clone();
+        peer.set_local_description(answer).await?;
+
+        client_conn.send(RelayMessage::Answer(answer_sdp))?;
+
+        // Recv ICE candidates, and later ICE restart offers, from client
+        // Takes ownership of client conn
+        let peer_clone = peer.clone();
+        runtime().spawn(async move {
+            while let Ok(msg) = client_conn.recv().await {
+                match msg {
+                    RelayMessage::IceCandidate { candidate, sdp_mid, sdp_m_line_index } => {
+                        let init = RTCIceCandidateInit {
+                            candidate,
+                            sdp_mid,
+                            sdp_mline_index: sdp_m_line_index,
+                            username_fragment: None
+                        };
+                        if let Err(e) = peer_clone.add_ice_candidate(init).await {
+                            warn!("WebRtcServer.handle_connection(): Failed to add ICE candidate: {}", e);
+                        }
+                    }
+                    RelayMessage::Offer(offer_sdp) => {
+                        if let Err(e) = Self::restart_ice(&peer_clone, &mut client_conn, offer_sdp).await {
+                            warn!("WebRtcServer.handle_connection(): Failed to answer ICE restart: {}", e);
+                        }
+                    }
+                    msg => warn!("WebRtcServer.handle_connection(): Recv unexpected while listening for ICE candidates: {:?}", msg),
+                }
+            }
+        });
+
+        let (reliable, unreliable) = timeout(timeout_ms, "data channels", async {
+            let (mut reliable, mut unreliable) = (None, None);
+            while reliable.is_none() || unreliable.is_none() {
+                let (label, data_channel) = data_channel_receiver.recv().await.ok_or("WebRtcServer.handle_connection(): Expected data channels to be ready")?;
+                match label.as_str() {
+                    RELIABLE_CHANNEL_LABEL => reliable = Some(data_channel),
+                    _ => unreliable = Some(data_channel),
+                }
+            }
+            // on_open fires immediately for channels that opened in the meantime, so waiting on them in turn is fine
+            let reliable = reliable.unwrap().opened().await?;
+            let unreliable = unreliable.unwrap().opened().await?;
+            Ok::<_, Error>((reliable, unreliable))
+        }).await??;
+        let mut reliable = reliable;
+        timeout(timeout_ms, "handshake", Self::handshake(&mut reliable, password)).await??;
+        Ok(DataChannels::new(reliable, unreliable))
+    }
+
+    // The client re-offers with fresh ICE credentials when its connection to us drops
+    async fn restart_ice(peer: &RTCPeerConnection, client_conn: &mut SignalingClientConnection, offer_sdp: String) -> Result<(), Error> {
+        info!("WebRtcServer: Client is restarting ICE");
+        peer.set_remote_description(RTCSessionDescription::offer(offer_sdp)?).await?;
+        let answer = peer.create_answer(None).await?;
+        let answer_sdp = answer.sdp.clone();
+        peer.set_local_description(answer).await?;
+        client_conn.send(RelayMessage::Answer(answer_sdp))?;
+        Ok(())
+    }
+
+    // Once the connection drops, give the client until the restart timeout to recover it.
+    // Closing the peer closes its data channels, so the client then gets removed like any other.
+    fn monitor_ice(peer: &Arc<RTCPeerConnection>, restart_timeout_ms: u32) {
+        let weak_peer = Arc::downgrade(peer); // The peer owns this handler
+        let waiting = Arc::new(AtomicBool::new(false));
+        peer.on_ice_connection_state_change(Box::new(move |state: RTCIceConnectionState| {
+            info!("WebRtcServer: ICE connection state changed to {}", state);
+            let interrupted = matches!(state, RTCIceConnectionState::Disconnected | RTCIceConnectionState::Failed);
+            if interrupted && !waiting.swap(true, Ordering::SeqCst) {
+                let (weak_peer, waiting) = (weak_peer.clone(), waiting.clone());
+                runtime().spawn(async move {
+                    sleep(restart_timeout_ms).await;
+                    waiting.store(false, Ordering::SeqCst);
+                    let Some(peer) = weak_peer.upgrade() else {
+                        return;
+                    };
+                    if !matches!(peer.ice_connection_state(), RTCIceConnectionState::Connected | RTCIceConnectionState::Completed) {
+                        warn!("WebRtcServer: Connection didn't recover within {}ms, dropping client", restart_timeout_ms);
+                        let _ = peer.close().await;
+                    }
+                });
+            }
+            Box::pin(async {})
+        }));
+    }
+
+    // Refuse clients speaking another protocol version or without the password, before their packets reach renet
+    async fn handshake(channel: &mut SendRecvCallbackChannel, password: &Option<String>) -> Result<(), Error> {
+        let refusal = match channel.recv().await? {
+            HandshakeMessage::Hello { version, .. } if version != PROTOCOL_VERSION => {
+                channel.send(HandshakeMessage::Incompatible { version: PROTOCOL_VERSION })?;
+                format!("Incompatible client version {}, ours is {}", version, PROTOCOL_VERSION)
+            }
+            HandshakeMessage::Hello { password: given, .. } if password.is_some() && given != *password => {
+                channel.send(HandshakeMessage::WrongPassword)?;
+                "Wrong password".to_owned()
+            }
+            HandshakeMessage::Hello { .. } => {
+                channel.send(HandshakeMessage::Welcome)?;
+                return Ok(());
+            }
+            msg => return Err(format!("Expected hello, got {:?}", msg).into()),
+        };
+        // Dropping the channels closes the peer connection, so give the reply time to arrive first
+        sleep(HANDSHAKE_LINGER_MS).await;
+        Err(refusal.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::loopback::{block_on, data_channels_pair, registered_server, LoopbackChannel}, *};
+
+    // Nothing listens there, so re-registering keeps failing
+    fn config() -> NetworkConfig {
+        NetworkConfig { signaling_url: "ws://127.0.0.1:9".to_owned(), ..Default::default() }
+    }
+
+    #[test]
+    fn re_registers_only_when_the_entry_changes() {
+        block_on(async {
+            let (server, mut hub) = registered_server(&config()).await;
+            assert!(server.is_registered());
+            server.update_entry(|entry| entry.players = 3);
+            server.update_entry(|entry| entry.players = 3);
+            server.unregister();
+            let update = hub.recv().await.unwrap();
+            assert_eq!(update["action"], "register");
+            assert_eq!(update["players"], 3);
+            assert_eq!(hub.recv().await.unwrap()["action"], "unregister");
+            assert!(hub.recv().await.is_err(), "Unregistering should close the signaling channel");
+            assert!(!server.is_registered());
+        });
+    }
+
+    #[test]
+    fn losing_the_hub_keeps_clients_connected() {
+        block_on(async {
+            let (server, hub) = registered_server(&config()).await;
+            let (channels, peer) = data_channels_pair().await;
+            server.add_client("a".to_owned(), channels);
+            assert_eq!(server.new_clients(), vec!["a".to_owned()]);
+            hub.close();
+            while server.is_registered() {
+                sleep(10).await;
+            }
+            assert!(!peer.is_closed());
+            assert!(server.clients().contains_key("a"));
+
+            // Closing hangs up on them, and stops trying to re-register
+            server.close();
+            assert!(peer.is_closed());
+            assert!(server.clients().is_empty());
+        });
+    }
+
+    // The client's end says hello, the server's end answers
+    async fn handshake(hello: HandshakeMessage, password: Option<String>) -> (Result<(), Error>, HandshakeMessage) {
+        let (server, client) = LoopbackChannel::pair();
+        server.open();
+        let (mut server, mut client) = (SendRecvCallbackChannel::new(Box::new(server)).await.unwrap(), SendRecvCallbackChannel::new(Box::new(client)).await.unwrap());
+        client.send(hello).unwrap();
+        let (result, reply) = tokio::join!(AsyncWebRtcServer::handshake(&mut server, &password), client.recv());
+        (result, reply.unwrap())
+    }
+
+    fn hello(password: Option<&str>) -> HandshakeMessage {
+        HandshakeMessage::Hello { version: PROTOCOL_VERSION, password: password.map(str::to_owned) }
+    }
+
+    #[test]
+    fn handshake_checks_version_and_password() {
+        let secret = || Some("secret".to_owned());
+        // Refusals linger before failing, so they run side by side
+        let (welcome, welcome_with_password, wrong_password, no_password, incompatible) = block_on(async { tokio::join!(
+            handshake(hello(None), None),
+            handshake(hello(Some("secret")), secret()),
+            handshake(hello(Some("guess")), secret()),
+            handshake(hello(None), secret()),
+            handshake(HandshakeMessage::Hello { version: PROTOCOL_VERSION + 1, password: None }, None)
+        ) });
+        assert!(matches!(welcome, (Ok(()), HandshakeMessage::Welcome)));
+        assert!(matches!(welcome_with_password, (Ok(()), HandshakeMessage::Welcome)));
+        assert!(matches!(wrong_password, (Err(_), HandshakeMessage::WrongPassword)));
+        assert!(matches!(no_password, (Err(_), HandshakeMessage::WrongPassword)));
+        assert!(matches!(incompatible, (Err(_), HandshakeMessage::Incompatible { version: PROTOCOL_VERSION })));
+    }
+
+    #[test]
+    fn failed_negotiation_leaves_the_hub_usable() {
+        block_on(async {
+            let (server, hub) = registered_server(&config()).await;
+            // The server waits for an offer first
+            hub.relay("a", RelayMessage::Answer("answer".to_owned()));
+            hub.relay("b", RelayMessage::Answer("answer".to_owned()));
+            sleep(100).await;
+            assert!(server.is_registered());
+            assert!(server.clients().is_empty());
+            server.close();
+        });
+    }
+}
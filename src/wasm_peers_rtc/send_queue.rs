@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+// Above this, a data channel is still working through earlier sends, so we hold back
+pub const MAX_BUFFERED_AMOUNT: u32 = 64 * 1024;
+// A peer this far behind on reliable packets isn't going to catch up
+pub const MAX_QUEUED_BYTES: usize = 1024 * 1024;
+
+// Outbound packets of one peer, held back while its data channels are congested.
+// Reliable packets wait their turn. Unreliable ones are dropped instead, renet sends fresher ones every tick anyway.
+#[derive(Default)]
+pub struct SendQueue {
+    reliable: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    dropped: u64
+}
+
+impl SendQueue {
+    pub fn push_reliable(&mut self, packet: &[u8]) -> Result<(), String> {
+        if self.queued_bytes + packet.len() > MAX_QUEUED_BYTES {
+            return Err(format!("Send queue is full with {} bytes", self.queued_bytes));
+        }
+        self.queued_bytes += packet.len();
+        self.reliable.push_back(packet.to_vec());
+        Ok(())
+    }
+
+    // By the time a congested channel could take it, the packet would be stale
+    pub fn send_unreliable<E>(&mut self, packet: &[u8], buffered_amount: u32, send: impl FnOnce(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        if buffered_amount >= MAX_BUFFERED_AMOUNT {
+            self.dropped += 1;
+            return Ok(());
+        }
+        send(packet)
+    }
+
+    // Sends queued reliable packets in order, until the channel is congested
+    pub fn flush<E>(&mut self, buffered_amount: impl Fn() -> u32, mut send: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        while buffered_amount() < MAX_BUFFERED_AMOUNT {
+            let Some(packet) = self.reliable.pop_front() else {
+                break;
+            };
+            self.queued_bytes -= packet.len();
+            send(&packet)?;
+        }
+        Ok(())
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.reliable.len()
+    }
+
+    // Unreliable packets dropped under pressure, since the connection opened
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    // A fake data channel: what was sent, and how much of it it's still working through
+    #[derive(Default)]
+    struct Channel {
+        sent: Vec<Vec<u8>>,
+        buffered: Cell<u32>
+    }
+
+    impl Channel {
+        fn send(&mut self, packet: &[u8]) -> Result<(), ()> {
+            self.buffered.set(self.buffered.get() + packet.len() as u32);
+            self.sent.push(packet.to_vec());
+            Ok(())
+        }
+    }
+
+    fn packet(i: u8, len: usize) -> Vec<u8> {
+        vec![i; len]
+    }
+
+    #[test]
+    fn flushes_reliable_packets_in_order_until_congested() {
+        let mut queue = SendQueue::default();
+        let len = MAX_BUFFERED_AMOUNT as usize / 4;
+        for i in 0..6 {
+            queue.push_reliable(&packet(i, len)).unwrap();
+        }
+        let channel = RefCell::new(Channel::default());
+        queue.flush(|| channel.borrow().buffered.get(), |packet| channel.borrow_mut().send(packet)).unwrap();
+        assert_eq!(channel.borrow().sent, (0..4).map(|i| packet(i, len)).collect::<Vec<_>>());
+        assert_eq!(queue.queued_packets(), 2);
+
+        // Once the channel caught up, the rest follows in order
+        channel.borrow().buffered.set(0);
+        queue.flush(|| channel.borrow().buffered.get(), |packet| channel.borrow_mut().send(packet)).unwrap();
+        assert_eq!(channel.borrow().sent, (0..6).map(|i| packet(i, len)).collect::<Vec<_>>());
+        assert_eq!(queue.queued_packets(), 0);
+    }
+
+    #[test]
+    fn refuses_reliable_packets_past_the_limit_until_flushed() {
+        let mut queue = SendQueue::default();
+        let len = MAX_QUEUED_BYTES / 4;
+        for i in 0..4 {
+            queue.push_reliable(&packet(i, len)).unwrap();
+        }
+        assert_eq!(queue.queued_bytes, MAX_QUEUED_BYTES);
+        assert!(queue.push_reliable(&packet(4, 1)).is_err());
+
+        // A single packet this big congests the channel
+        let channel = RefCell::new(Channel::default());
+        queue.flush(|| channel.borrow().buffered.get(), |packet| channel.borrow_mut().send(packet)).unwrap();
+        assert_eq!(channel.borrow().sent.len(), 1);
+        assert_eq!(queue.queued_bytes, MAX_QUEUED_BYTES - len);
+        queue.push_reliable(&packet(4, len)).unwrap();
+        assert!(queue.push_reliable(&packet(5, 1)).is_err());
+    }
+
+    #[test]
+    fn drops_unreliable_packets_under_pressure() {
+        let mut queue = SendQueue::default();
+        let mut channel = Channel::default();
+        queue.send_unreliable(&packet(0, 10), 0, |packet| channel.send(packet)).unwrap();
+        queue.send_unreliable(&packet(1, 10), MAX_BUFFERED_AMOUNT - 1, |packet| channel.send(packet)).unwrap();
+        queue.send_unreliable(&packet(2, 10), MAX_BUFFERED_AMOUNT, |packet| channel.send(packet)).unwrap();
+        queue.send_unreliable(&packet(3, 10), MAX_BUFFERED_AMOUNT * 2, |packet| channel.send(packet)).unwrap();
+        assert_eq!(channel.sent, vec![packet(0, 10), packet(1, 10)]);
+        assert_eq!(queue.dropped_packets(), 2);
+        assert_eq!(queue.queued_packets(), 0);
+    }
+}
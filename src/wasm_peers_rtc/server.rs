@@ -1,12 +1,14 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
 
 use bevy::{prelude::*, utils::HashSet};
 use bevy_inspector_egui::quick::StateInspectorPlugin;
-use bevy_replicon::replicon_core::NetworkChannels;
+use bevy_renet::{RenetReceive, RenetSend, RenetServerPlugin};
+use bevy_replicon::{replicon_core::NetworkChannels, server::ServerSet};
 use renet::{ClientId, ConnectionConfig, RenetServer};
-use wasm_bindgen_futures::spawn_local;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::{closure::Closure, JsCast};
 
-use super::{callback_channel::SendRecvCallbackChannel, signaling::ConnectionId, webrtc::AsyncWebRtcServer};
+use super::{callback_channel::DataChannels, config::NetworkConfig, signaling::ConnectionId, simulator::NetworkSimulator, util::spawn, webrtc::AsyncWebRtcServer};
 
 pub struct WebRtcServerPlugin {
     pub is_headless: bool
@@ -19,9 +21,13 @@ impl Plugin for WebRtcServerPlugin {
         if !self.is_headless {
             app.add_plugins(StateInspectorPlugin::<WebRtcServerState>::new());
         }
+        app.configure_sets(PreUpdate, WebRtcServerSet::Receive.in_set(RenetReceive).after(RenetServerPlugin::update_system).before(ServerSet::Receive));
+        app.configure_sets(PostUpdate, WebRtcServerSet::Send.in_set(RenetSend).after(ServerSet::Send));
         app.add_systems(PreUpdate, Self::update_server_state);
         app.add_systems(OnExit(WebRtcServerState::Offline), Self::server_online);
-        app.add_systems(Update, Self::update_server_clients.run_if(not(in_state(WebRtcServerState::Offline)))); // TODO fix 1 frame delay by moving to PreUpdate, using system sets to run after update_server_state but before renet handles packets
+        app.add_systems(OnEnter(WebRtcServerState::Offline), Self::server_offline);
+        app.add_systems(PreUpdate, Self::receive_packets.in_set(WebRtcServerSet::Receive).after(Self::update_server_state).run_if(not(in_state(WebRtcServerState::Offline))));
+        app.add_systems(PostUpdate, Self::send_packets.in_set(WebRtcServerSet::Send).run_if(not(in_state(WebRtcServerState::Offline))));
     }
 }
 
@@ -36,10 +42,11 @@ impl WebRtcServerPlugin {
     ) {
         let removed = server.is_none() && *exists;
         let changed = server.as_ref().map_or(false, |s| s.is_changed());
-        if removed || changed || *state == WebRtcServerState::Registering || *state == WebRtcServerState::Registered {
+        if removed || changed || *state != WebRtcServerState::Offline {
             let next = match &server {
                 Some(s) if s.is_listening() => WebRtcServerState::Listening,
-                Some(s) if s.server.borrow().is_some() => WebRtcServerState::Registered,
+                Some(s) if s.is_reconnecting() => WebRtcServerState::Reconnecting,
+                Some(s) if s.server.lock().unwrap().is_some() => WebRtcServerState::Registered,
                 Some(_) => WebRtcServerState::Registering,
                 None => WebRtcServerState::Offline,
             };
@@ -61,11 +68,22 @@ impl WebRtcServerPlugin {
         world.insert_resource(server);
     }
 
-    fn update_server_clients(
-        mut rtc_server: NonSendMut<WebRtcServer>,
+    fn server_offline(mut commands: Commands) {
+        commands.remove_resource::<RenetServer>();
+    }
+
+    // Feeds renet what arrived since last frame, right after it updated, so the game sees it this frame
+    fn receive_packets(
+        rtc_server: Option<NonSendMut<WebRtcServer>>, // Gone for the rest of the frame after closing
         mut renet_server: ResMut<RenetServer>,
+        mut simulator: ResMut<NetworkSimulator>,
+        time: Res<Time<Real>>
     ) {
+        let Some(mut rtc_server) = rtc_server else {
+            return;
+        };
         let mut client_change = false;
+        let now = time.elapsed();
 
         // Handle new clients
         for new_client in rtc_server.new_clients() {
@@ -79,47 +97,104 @@ impl WebRtcServerPlugin {
 
         // Handle transport-disconnected clients
         let mut disconnect = HashSet::new();
-        for (connection, channel) in rtc_server.clients() {
-            if channel.is_closed() {
+        for (connection, channels) in rtc_server.clients() {
+            if channels.is_closed() {
                 disconnect.insert(connection.to_owned());
-                client_change = true;
             }
         }
 
-        // Handle incoming packets
-        for (connection, mut channel) in rtc_server.clients() {
-            let client_id = rtc_server.connection_to_client.borrow().get(&connection).unwrap().to_owned();
-            let packets: Vec<Vec<u8>> = channel.drain().unwrap();
-            for packet in packets {
-                renet_server.process_packet_from(&packet, client_id).unwrap();
+        // Handle incoming packets. Garbage only costs the peer that sent it its connection
+        for (connection, mut channels) in rtc_server.clients() {
+            // Connected after new_clients() above, picked up next frame
+            let Some(client_id) = rtc_server.connection_to_client.borrow().get(&connection).copied() else {
+                continue;
+            };
+            match channels.drain_packets() {
+                Ok(packets) => for packet in simulator.incoming(&connection, now, packets) {
+                    if let Err(e) = renet_server.process_packet_from(&packet, client_id) {
+                        warn!("Dropping packet from client {}: {}", client_id, e);
+                        break;
+                    }
+                },
+                Err(e) => {
+                    warn!("Disconnecting client {}: protocol error: {}", client_id, e);
+                    disconnect.insert(connection);
+                }
+            }
+        }
+
+        Self::disconnect_clients(&mut rtc_server, &mut renet_server, &mut simulator, disconnect, client_change);
+    }
+
+    // Sends whatever renet queued this frame, after replicon wrote the frame's replication
+    fn send_packets(
+        rtc_server: Option<NonSendMut<WebRtcServer>>, // Gone for the rest of the frame after closing
+        mut renet_server: ResMut<RenetServer>,
+        mut simulator: ResMut<NetworkSimulator>,
+        time: Res<Time<Real>>
+    ) {
+        let Some(mut rtc_server) = rtc_server else {
+            return;
+        };
+        let now = time.elapsed();
+
+        // Handle clients renet disconnected after a protocol error, or the game disconnected, e.g. after rejecting them
+        let mut disconnect = HashSet::new();
+        for client_id in renet_server.disconnections_id() {
+            if let Some(connection) = rtc_server.client_to_connection.borrow().get(&client_id) {
+                if let Some(reason) = renet_server.disconnect_reason(client_id) {
+                    info!("Disconnecting client {}: {}", client_id, reason);
+                }
+                disconnect.insert(connection.to_owned());
             }
         }
 
         // Handle outgoing packets
         for client_id in renet_server.clients_id() {
-            let connection_id = rtc_server.client_to_connection.borrow().get(&client_id).unwrap().to_owned();
-            let packets = renet_server.get_packets_to_send(client_id).unwrap();
+            let Some(connection_id) = rtc_server.client_to_connection.borrow().get(&client_id).cloned() else {
+                continue;
+            };
+            let Ok(packets) = renet_server.get_packets_to_send(client_id) else {
+                continue;
+            };
+            let packets = simulator.outgoing(&connection_id, now, packets);
             if let Some(connection) = rtc_server.clients().get_mut(&connection_id) {
                 if !connection.is_closed() {
-                    for packet in packets {
-                        if let Err(_) = connection.send(packet) {
-                            disconnect.insert(connection_id.to_owned());
-                            break;
-                        }
+                    // Whatever congestion held back earlier goes first
+                    let sent = connection.flush().and_then(|_| packets.iter().try_for_each(|packet| connection.send_packet(packet)));
+                    if let Err(e) = sent {
+                        warn!("Disconnecting client {}: failed to send: {:?}", client_id, e);
+                        disconnect.insert(connection_id.to_owned());
                     }
                 }
             }
         }
 
-        if disconnect.len() > 0 {
+        Self::disconnect_clients(&mut rtc_server, &mut renet_server, &mut simulator, disconnect, false);
+    }
+
+    fn disconnect_clients(
+        rtc_server: &mut WebRtcServer,
+        renet_server: &mut RenetServer,
+        simulator: &mut NetworkSimulator,
+        disconnect: HashSet<ConnectionId>,
+        client_change: bool
+    ) {
+        if !disconnect.is_empty() {
             info!("Closing {:?}", disconnect);
         }
+        let client_change = client_change || !disconnect.is_empty();
         for connection in disconnect {
+            if let Some(channels) = rtc_server.clients().get(&connection) {
+                channels.close();
+            }
             rtc_server.remove_client(&connection);
-            let client = *rtc_server.connection_to_client.borrow().get(&connection).unwrap();
-            renet_server.remove_connection(client);
-            rtc_server.connection_to_client.borrow_mut().remove(&connection);
-            rtc_server.client_to_connection.borrow_mut().remove(&client);
+            simulator.remove(&connection);
+            let client = rtc_server.connection_to_client.borrow_mut().remove(&connection);
+            if let Some(client) = client {
+                renet_server.remove_connection(client);
+                rtc_server.client_to_connection.borrow_mut().remove(&client);
+            }
         }
 
         if client_change {
@@ -128,56 +203,216 @@ impl WebRtcServerPlugin {
     }
 }
 
+// Where the transport hands packets to renet and takes them back, around renet's and replicon's own sets
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WebRtcServerSet {
+    Receive, // In PreUpdate
+    Send // In PostUpdate
+}
+
 #[derive(States, Debug, Default, Hash, Eq, PartialEq, Clone, Reflect)]
 pub enum WebRtcServerState {
     #[default]
     Offline,
     Registering,
     Registered,
-    Listening
+    Listening,
+    Reconnecting // Lost the signaling server: existing clients stay, new ones can't find us
 }
 
 #[derive(Clone)]
 pub struct WebRtcServer {
-    server: Rc<RefCell<Option<AsyncWebRtcServer>>>,
+    server: Arc<Mutex<Option<AsyncWebRtcServer>>>,
     client_to_connection: Rc<RefCell<HashMap<ClientId, ConnectionId>>>,
     connection_to_client: Rc<RefCell<HashMap<ConnectionId, ClientId>>>,
     next_client_id: u64,
+    closed: Arc<AtomicBool>, // Closed before the AsyncWebRtcServer was even up
+    #[cfg(target_arch = "wasm32")]
+    _unload: Rc<UnloadListener>
 }
 
 impl WebRtcServer {
-    pub fn new(signaling_url: String, game_name: String, server_name: String) -> WebRtcServer {
+    // AsyncWebRtcServer is only !Send on wasm, where everything runs on one thread anyway
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn new(config: NetworkConfig) -> WebRtcServer {
+        let async_server = Arc::new(Mutex::new(None));
         let server = WebRtcServer {
-            server: Rc::new(RefCell::new(None)),
+            server: async_server.clone(),
             client_to_connection: Rc::new(RefCell::new(HashMap::new())),
             connection_to_client: Rc::new(RefCell::new(HashMap::new())),
             next_client_id: 1,
+            closed: Arc::new(AtomicBool::new(false)),
+            #[cfg(target_arch = "wasm32")]
+            _unload: Rc::new(UnloadListener::new(async_server.clone()))
         };
-        let server_clone = server.clone();
-        spawn_local(async move {
-            match AsyncWebRtcServer::new(&signaling_url, &game_name, &server_name).await {
-                Ok(s) => *server_clone.server.borrow_mut() = Some(s),
+        let closed = server.closed.clone();
+        spawn(async move {
+            match AsyncWebRtcServer::new(&config).await {
+                Ok(s) if closed.load(Ordering::Relaxed) => s.close(),
+                Ok(s) => *async_server.lock().unwrap() = Some(s),
                 Err(e) => warn!("Error creating AsyncWebRtcServer: {:?}", e),
             }
         });
         server
     }
 
+    // Leave the server browser for good, existing clients stay connected
+    pub fn unregister(&self) {
+        if let Some(s) = self.server.lock().unwrap().as_ref() {
+            s.unregister();
+        }
+    }
+
+    // Unregister and hang up on every client
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(s) = self.server.lock().unwrap().as_ref() {
+            s.close();
+        }
+    }
+
     pub fn is_listening(&self) -> bool {
-        self.server.borrow().is_some() // TODO: is_some_and(AsyncServer.is_listening)
+        self.server.lock().unwrap().as_ref().is_some_and(|s| s.is_registered())
+    }
+
+    pub fn is_reconnecting(&self) -> bool {
+        self.server.lock().unwrap().as_ref().is_some_and(|s| !s.is_registered())
+    }
+
+    // Advertised in the server browser
+    pub fn set_players(&self, players: u32) {
+        if let Some(s) = self.server.lock().unwrap().as_ref() {
+            s.update_entry(|entry| entry.players = players);
+        }
     }
 
-    pub fn clients(&self) -> HashMap<ConnectionId, SendRecvCallbackChannel> {
-        self.server.borrow().as_ref().map_or(HashMap::new(), |s| s.clients.borrow().clone())
+    // Which peer a renet client is talking through
+    pub fn connection(&self, client_id: ClientId) -> Option<ConnectionId> {
+        self.client_to_connection.borrow().get(&client_id).cloned()
+    }
+
+    pub fn clients(&self) -> HashMap<ConnectionId, DataChannels> {
+        self.server.lock().unwrap().as_ref().map_or(HashMap::new(), |s| s.clients())
     }
 
     pub fn remove_client(&mut self, connection: &str) {
-        if let Some(s) = self.server.borrow_mut().as_mut() {
-            s.clients.borrow_mut().remove(connection);
+        if let Some(s) = self.server.lock().unwrap().as_ref() {
+            s.remove_client(connection);
         }
     }
 
     pub fn new_clients(&mut self) -> Vec<ConnectionId> {
-        self.server.borrow().as_ref().map_or(Vec::new(), |s| s.new_clients.borrow_mut().drain(..).collect())
+        self.server.lock().unwrap().as_ref().map_or(Vec::new(), |s| s.new_clients())
+    }
+}
+
+// Closing the tab ends the frame loop, so this is the last chance to leave the server list and hang up
+#[cfg(target_arch = "wasm32")]
+struct UnloadListener(Closure<dyn FnMut()>);
+
+#[cfg(target_arch = "wasm32")]
+impl UnloadListener {
+    fn new(server: Arc<Mutex<Option<AsyncWebRtcServer>>>) -> UnloadListener {
+        let closure = Closure::<dyn FnMut()>::new(move || {
+            if let Some(s) = server.lock().unwrap().as_ref() {
+                s.close();
+            }
+        });
+        if let Some(window) = web_sys::window() {
+            let _ = window.add_event_listener_with_callback("pagehide", closure.as_ref().unchecked_ref());
+        }
+        UnloadListener(closure)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for UnloadListener {
+    fn drop(&mut self) {
+        if let Some(window) = web_sys::window() {
+            let _ = window.remove_event_listener_with_callback("pagehide", self.0.as_ref().unchecked_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use renet::{DefaultChannel, RenetClient};
+
+    use super::{super::native::loopback::{block_on, data_channels_pair, registered_server}, *};
+
+    // A server with one peer "a" connected, and that peer's end of its data channels
+    fn world_with_peer() -> (World, DataChannels) {
+        let config = NetworkConfig { signaling_url: "ws://127.0.0.1:9".to_owned(), ..default() };
+        let ((server, _hub), (channels, peer)) = block_on(async { (registered_server(&config).await, data_channels_pair().await) });
+        server.add_client("a".to_owned(), channels);
+
+        let mut world = World::new();
+        world.insert_resource(RenetServer::new(ConnectionConfig::default()));
+        world.init_resource::<NetworkSimulator>();
+        world.init_resource::<Time<Real>>();
+        world.insert_non_send_resource(WebRtcServer {
+            server: Arc::new(Mutex::new(Some(server))),
+            client_to_connection: Rc::new(RefCell::new(HashMap::new())),
+            connection_to_client: Rc::new(RefCell::new(HashMap::new())),
+            next_client_id: 1,
+            closed: Arc::new(AtomicBool::new(false))
+        });
+        world.run_system_once(WebRtcServerPlugin::receive_packets);
+        (world, peer)
+    }
+
+    const CLIENT: ClientId = ClientId::from_raw(1);
+
+    #[test]
+    fn pumps_packets_both_ways() {
+        let (mut world, mut peer) = world_with_peer();
+        assert!(world.resource::<RenetServer>().is_connected(CLIENT));
+        assert_eq!(world.non_send_resource::<WebRtcServer>().connection(CLIENT), Some("a".to_owned()));
+
+        let mut client = RenetClient::new(ConnectionConfig::default());
+        client.set_connected();
+        client.send_message(DefaultChannel::ReliableOrdered, "ping");
+        client.send_message(DefaultChannel::Unreliable, "ping");
+        for packet in client.get_packets_to_send() {
+            peer.send_packet(&packet).unwrap();
+        }
+        world.run_system_once(WebRtcServerPlugin::receive_packets);
+        let mut renet_server = world.resource_mut::<RenetServer>();
+        assert_eq!(renet_server.receive_message(CLIENT, DefaultChannel::ReliableOrdered).as_deref(), Some(&b"ping"[..]));
+        assert_eq!(renet_server.receive_message(CLIENT, DefaultChannel::Unreliable).as_deref(), Some(&b"ping"[..]));
+
+        renet_server.send_message(CLIENT, DefaultChannel::ReliableOrdered, "pong");
+        renet_server.send_message(CLIENT, DefaultChannel::Unreliable, "pong");
+        world.run_system_once(WebRtcServerPlugin::send_packets);
+        for packet in peer.drain_packets().unwrap() {
+            client.process_packet(&packet);
+        }
+        assert_eq!(client.receive_message(DefaultChannel::ReliableOrdered).as_deref(), Some(&b"pong"[..]));
+        assert_eq!(client.receive_message(DefaultChannel::Unreliable).as_deref(), Some(&b"pong"[..]));
+        world.non_send_resource::<WebRtcServer>().close();
+    }
+
+    #[test]
+    fn closed_data_channels_disconnect_the_client() {
+        let (mut world, peer) = world_with_peer();
+        peer.close();
+        world.run_system_once(WebRtcServerPlugin::receive_packets);
+        assert!(!world.resource::<RenetServer>().is_connected(CLIENT));
+        let rtc_server = world.non_send_resource::<WebRtcServer>();
+        assert!(rtc_server.clients().is_empty());
+        assert_eq!(rtc_server.connection(CLIENT), None);
+        rtc_server.close();
+    }
+
+    #[test]
+    fn garbage_costs_the_peer_its_connection() {
+        let (mut world, mut peer) = world_with_peer();
+        peer.send_packet(&[0, 255, 255, 255]).unwrap();
+        world.run_system_once(WebRtcServerPlugin::receive_packets);
+        world.run_system_once(WebRtcServerPlugin::send_packets);
+        assert!(peer.is_closed());
+        assert!(world.non_send_resource::<WebRtcServer>().clients().is_empty());
+        world.non_send_resource::<WebRtcServer>().close();
     }
 }
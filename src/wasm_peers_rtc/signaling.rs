@@ -1,24 +1,51 @@
 use std::collections::HashMap;
+#[cfg(target_arch = "wasm32")]
+use std::{cell::RefCell, rc::Rc};
 
+#[cfg(target_arch = "wasm32")]
+use bevy::log::warn;
 use serde::{Serialize, Deserialize};
+#[cfg(target_arch = "wasm32")]
 use wasm_bindgen::JsValue;
 
+#[cfg(target_arch = "wasm32")]
 use super::{callback_channel::SendRecvCallbackChannel, deque_channel::{JsSender, JsDequeChannel, JsReceiver}};
 
 pub type ConnectionId = String;
 
-#[derive(Serialize, Deserialize, Debug)]
+// First exchange over a peer's reliable data channel, before any renet packets flow
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action")]
+pub enum HandshakeMessage {
+    #[serde(rename = "hello")]
+    Hello {
+        version: u32,
+        #[serde(default)]
+        password: Option<String> // Checked here, so nobody without it gets to see the game
+    },
+    #[serde(rename = "welcome")]
+    Welcome,
+    #[serde(rename = "incompatible")]
+    Incompatible {
+        version: u32 // The server's
+    },
+    #[serde(rename = "wrong_password")]
+    WrongPassword
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "action")]
 pub enum SignalingMessage {
     #[serde(rename = "list")]
     List {
         servers: HashMap<ConnectionId, ServerEntry>
     },
+    #[serde(rename = "refresh")]
+    Refresh, // Ask for a new list. Not every hub knows it, see WebRtcBrowserPlugin::browser_refresh()
     #[serde(rename = "register")]
-    Register {
-        game: String,
-        name: String
-    },
+    Register(ServerEntry), // Sent again whenever the entry changes
+    #[serde(rename = "unregister")]
+    Unregister, // Leave the list right away, rather than once the hub notices the websocket is gone
     #[serde(rename = "relay")]
     Relay {
         #[serde(skip_serializing)]
@@ -28,13 +55,26 @@ pub enum SignalingMessage {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Fields other than name and game may be missing when listed by older servers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ServerEntry {
     pub name: String,
-    pub game: String
+    pub game: String,
+    #[serde(default)]
+    pub players: u32,
+    #[serde(default)]
+    pub max_players: u32,
+    #[serde(default)]
+    pub mode: String,
+    #[serde(default)]
+    pub map: String,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub password: bool
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", content = "data")]
 pub enum RelayMessage {
     Offer(String),
@@ -46,19 +86,25 @@ pub enum RelayMessage {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
+type DemuxClients = Rc<RefCell<HashMap<ConnectionId, JsSender<RelayMessage>>>>;
+
+#[cfg(target_arch = "wasm32")]
 pub struct SignalingDemux {
     signaling_server: SendRecvCallbackChannel,
-    clients: HashMap<ConnectionId, JsSender<RelayMessage>>
+    clients: DemuxClients // Entries remove themselves once their SignalingClientConnection is dropped
 }
 
+#[cfg(target_arch = "wasm32")]
 pub enum SignalingDemuxRecv {
     System(SignalingMessage),
     Relay(ConnectionId, SignalingClientConnection)
 }
 
+#[cfg(target_arch = "wasm32")]
 impl SignalingDemux {
     pub fn new(signaling_server: SendRecvCallbackChannel) -> SignalingDemux {
-        SignalingDemux { signaling_server, clients: HashMap::new() }
+        SignalingDemux { signaling_server, clients: Rc::new(RefCell::new(HashMap::new())) }
     }
     pub fn _send(&mut self, msg: SignalingMessage) -> Result<(), JsValue> {
         self.signaling_server.send(msg)
@@ -66,21 +112,32 @@ impl SignalingDemux {
 
     pub async fn recv(&mut self) -> Result<SignalingDemuxRecv, JsValue> {
         loop {
-            let msg: SignalingMessage = self.signaling_server.recv().await?;
-            match msg {
-                SignalingMessage::Relay { src, dst: _dst, data } if self.clients.contains_key(&src) => {
-                    // Existing connection
-                    self.clients.get_mut(&src).unwrap().send(data)?;
+            let msg: SignalingMessage = match self.signaling_server.recv().await {
+                Ok(msg) => msg,
+                // A peer relaying garbage mustn't cost us the signaling server
+                Err(e) if !self.signaling_server.is_closed() => {
+                    warn!("SignalingDemux: ignoring malformed message: {:?}", e);
+                    continue;
                 }
+                Err(e) => return Err(e),
+            };
+            match msg {
                 SignalingMessage::Relay { src, dst: _dst, data } => {
+                    let existing = self.clients.borrow().get(&src).cloned();
+                    if let Some(sender) = existing {
+                        sender.send(data)?;
+                        continue;
+                    }
                     // New connection
                     let (sender, receiver) = JsDequeChannel::<RelayMessage>::channel();
                     sender.send(data)?;
-                    self.clients.insert(src.to_owned(), sender);
+                    self.clients.borrow_mut().insert(src.to_owned(), sender.clone());
                     return Ok(SignalingDemuxRecv::Relay(src.clone(), SignalingClientConnection {
                         connection_id: src,
                         signaling_server: self.signaling_server.clone(),
-                        receiver
+                        sender,
+                        receiver,
+                        clients: self.clients.clone()
                     }));
                 }
                 _ => {
@@ -93,12 +150,37 @@ impl SignalingDemux {
     }
 }
 
+// Without the signaling server, nobody will relay anything to the connections anymore
+#[cfg(target_arch = "wasm32")]
+impl Drop for SignalingDemux {
+    fn drop(&mut self) {
+        for sender in self.clients.borrow().values() {
+            let _ = sender.close();
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
 pub struct SignalingClientConnection {
     connection_id: String,
     signaling_server: SendRecvCallbackChannel, // send-only
-    receiver: JsReceiver<RelayMessage>
+    sender: JsSender<RelayMessage>, // The demux's end, to close it
+    receiver: JsReceiver<RelayMessage>,
+    clients: DemuxClients
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for SignalingClientConnection {
+    fn drop(&mut self) {
+        let mut clients = self.clients.borrow_mut();
+        // A newer connection from the same peer may have taken over the entry already
+        if clients.get(&self.connection_id).is_some_and(|sender| sender.same_channel(&self.sender)) {
+            clients.remove(&self.connection_id);
+        }
+    }
 }
 
+#[cfg(target_arch = "wasm32")]
 impl SignalingClientConnection {
     pub fn send(&mut self, msg: RelayMessage) -> Result<(), JsValue> {
         self.signaling_server.send(SignalingMessage::Relay {
@@ -118,13 +200,32 @@ impl SignalingClientConnection {
             signaling_server: self.signaling_server.clone()
         }
     }
+
+    // Lets whoever gave up on the connection end recv(), even while another task is blocked in it
+    pub fn closer(&self) -> SignalingClientCloser {
+        SignalingClientCloser { sender: self.sender.clone() }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub struct SignalingClientCloser {
+    sender: JsSender<RelayMessage>
+}
+
+#[cfg(target_arch = "wasm32")]
+impl SignalingClientCloser {
+    pub fn close(&self) {
+        let _ = self.sender.close();
+    }
 }
 
+#[cfg(target_arch = "wasm32")]
 pub struct SignalingClientSender {
     connection_id: String,
     signaling_server: SendRecvCallbackChannel, // send-only
 }
 
+#[cfg(target_arch = "wasm32")]
 impl SignalingClientSender {
     pub fn send(&mut self, msg: RelayMessage) -> Result<(), JsValue> {
         self.signaling_server.send(SignalingMessage::Relay {
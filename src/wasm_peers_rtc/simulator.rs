@@ -0,0 +1,187 @@
+use std::{collections::{HashMap, VecDeque}, time::Duration};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{config::{NetworkConditions, NetworkConfig}, signaling::ConnectionId, util::is_unreliable_packet};
+
+pub struct NetworkSimulatorPlugin {
+    pub is_headless: bool
+}
+
+impl Plugin for NetworkSimulatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkSimulator>();
+        if !self.is_headless {
+            app.add_systems(Update, Self::show_panel);
+        }
+    }
+}
+
+impl NetworkSimulatorPlugin {
+    // Toggled with F4
+    fn show_panel(
+        mut contexts: EguiContexts,
+        keys: Res<Input<KeyCode>>,
+        mut simulator: ResMut<NetworkSimulator>,
+        mut visible: Local<bool>
+    ) {
+        if keys.just_pressed(KeyCode::F4) {
+            *visible = !*visible;
+        }
+        if !*visible {
+            return;
+        }
+        let mut conditions = simulator.conditions.clone();
+        egui::Window::new("Network simulator").resizable(false).show(contexts.ctx_mut(), |ui| {
+            ui.add(egui::Slider::new(&mut conditions.latency_ms, 0..=1000).text("Latency (ms)"));
+            ui.add(egui::Slider::new(&mut conditions.jitter_ms, 0..=500).text("Jitter (ms)"));
+            ui.add(egui::Slider::new(&mut conditions.loss_percent, 0.0..=100.0).text("Loss (%)"));
+            ui.add(egui::Slider::new(&mut conditions.bandwidth_kbps, 0..=10000).text("Bandwidth (kbit/s, 0 = unlimited)"));
+            if ui.button("Reset").clicked() {
+                conditions = NetworkConditions { seed: conditions.seed, ..default() };
+            }
+        });
+        // Only touch the resource on changes, so change detection stays meaningful
+        if conditions != simulator.conditions {
+            simulator.conditions = conditions;
+        }
+    }
+}
+
+// Delays, drops and throttles packets per connection and direction, as configured by its conditions.
+// The transports route every packet through here, between their data channels and renet.
+#[derive(Resource)]
+pub struct NetworkSimulator {
+    pub conditions: NetworkConditions,
+    rng: StdRng,
+    links: HashMap<ConnectionId, Link>
+}
+
+impl FromWorld for NetworkSimulator {
+    fn from_world(world: &mut World) -> Self {
+        let conditions = world.get_resource::<NetworkConfig>().map(|config| config.conditions.clone()).unwrap_or_default();
+        NetworkSimulator::new(conditions)
+    }
+}
+
+impl NetworkSimulator {
+    pub fn new(conditions: NetworkConditions) -> NetworkSimulator {
+        let rng = match conditions.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        NetworkSimulator { conditions, rng, links: HashMap::new() }
+    }
+
+    // Packets received from `connection`, released once their simulated trip is over
+    pub fn incoming(&mut self, connection: &str, now: Duration, packets: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let link = self.links.entry(connection.to_owned()).or_default();
+        link.incoming.transmit(&self.conditions, &mut self.rng, now, packets)
+    }
+
+    // Packets to send to `connection`, released once their simulated trip is over
+    pub fn outgoing(&mut self, connection: &str, now: Duration, packets: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let link = self.links.entry(connection.to_owned()).or_default();
+        link.outgoing.transmit(&self.conditions, &mut self.rng, now, packets)
+    }
+
+    // Drops whatever is still in flight, so a new connection under the same ID starts empty
+    pub fn remove(&mut self, connection: &str) {
+        self.links.remove(connection);
+    }
+}
+
+#[derive(Default)]
+struct Link {
+    incoming: Pipe,
+    outgoing: Pipe
+}
+
+#[derive(Default)]
+struct Pipe {
+    in_flight: VecDeque<(Duration, Vec<u8>)>, // Ordered by arrival time
+    last_reliable_arrival: Duration,
+    free_at: Duration // When the capped bandwidth is available again
+}
+
+impl Pipe {
+    fn transmit(&mut self, conditions: &NetworkConditions, rng: &mut StdRng, now: Duration, packets: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        if !conditions.is_active() && self.in_flight.is_empty() {
+            return packets;
+        }
+        for packet in packets {
+            if rng.gen::<f32>() * 100. < conditions.loss_percent {
+                continue;
+            }
+            let mut sent_at = now;
+            if conditions.bandwidth_kbps > 0 {
+                // Packets wait for the ones before them to get through
+                let transmission = Duration::from_secs_f64(packet.len() as f64 * 8. / (conditions.bandwidth_kbps as f64 * 1000.));
+                self.free_at = self.free_at.max(now) + transmission;
+                sent_at = self.free_at;
+            }
+            let jitter_ms = conditions.jitter_ms as i64;
+            let delay_ms = (conditions.latency_ms as i64 + rng.gen_range(-jitter_ms..=jitter_ms)).max(0);
+            let mut arrival = sent_at + Duration::from_millis(delay_ms as u64);
+            if !is_unreliable_packet(&packet) {
+                // The reliable data channel delivers in order, so a late packet holds up the ones behind it
+                arrival = arrival.max(self.last_reliable_arrival);
+                self.last_reliable_arrival = arrival;
+            }
+            // The unreliable one doesn't, jitter may overtake earlier packets
+            let index = self.in_flight.partition_point(|(other, _)| *other <= arrival);
+            self.in_flight.insert(index, (arrival, packet));
+        }
+        let arrived = self.in_flight.iter().take_while(|(arrival, _)| *arrival <= now).count();
+        self.in_flight.drain(..arrived).map(|(_, packet)| packet).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELIABLE: u8 = 0; // renet's first byte of a reliable channel's packet
+    const UNRELIABLE: u8 = 1;
+
+    fn packets(kind: u8, count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![kind, i]).collect()
+    }
+
+    fn simulator(latency_ms: u32, jitter_ms: u32, loss_percent: f32) -> NetworkSimulator {
+        NetworkSimulator::new(NetworkConditions { latency_ms, jitter_ms, loss_percent, seed: Some(42), ..default() })
+    }
+
+    #[test]
+    fn delays_packets_by_latency() {
+        let mut simulator = simulator(100, 0, 0.);
+        assert!(simulator.incoming("a", Duration::ZERO, packets(RELIABLE, 10)).is_empty());
+        assert!(simulator.incoming("a", Duration::from_millis(99), vec![]).is_empty());
+        assert_eq!(simulator.incoming("a", Duration::from_millis(100), vec![]), packets(RELIABLE, 10));
+    }
+
+    #[test]
+    fn drops_the_same_packets_for_the_same_seed() {
+        let run = || {
+            let mut simulator = simulator(0, 0, 50.);
+            simulator.outgoing("a", Duration::ZERO, packets(UNRELIABLE, 200))
+        };
+        let delivered = run();
+        assert!((60..140).contains(&delivered.len()), "{} of 200 delivered at 50% loss", delivered.len());
+        assert_eq!(delivered, run());
+    }
+
+    #[test]
+    fn jitter_reorders_only_unreliable_packets() {
+        let mut simulator = simulator(100, 50, 0.);
+        let sent: Vec<Vec<u8>> = packets(RELIABLE, 50).into_iter().chain(packets(UNRELIABLE, 50)).collect();
+        let mut received = simulator.incoming("a", Duration::ZERO, sent);
+        received.extend(simulator.incoming("a", Duration::from_millis(150), vec![]));
+        assert_eq!(received.len(), 100);
+        let (reliable, unreliable): (Vec<_>, Vec<_>) = received.into_iter().partition(|packet| packet[0] == RELIABLE);
+        assert_eq!(reliable, packets(RELIABLE, 50));
+        assert_ne!(unreliable, packets(UNRELIABLE, 50));
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use renet::{ClientId, NetworkInfo, RenetServer};
+#[cfg(target_arch = "wasm32")]
+use renet::RenetClient;
+
+#[cfg(target_arch = "wasm32")]
+use super::client::{WebRtcClient, WebRtcClientState};
+use super::{callback_channel::DataChannels, server::WebRtcServer};
+
+pub struct NetworkStatsPlugin {
+    pub is_headless: bool
+}
+
+impl Plugin for NetworkStatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkStats>();
+        app.add_systems(Update, Self::collect_server_stats.run_if(resource_exists::<RenetServer>()));
+        app.add_systems(Update, Self::clear_server_stats.run_if(resource_removed::<RenetServer>()));
+        #[cfg(target_arch = "wasm32")]
+        {
+            app.add_systems(Update, Self::collect_client_stats.run_if(in_state(WebRtcClientState::Connected)));
+            app.add_systems(OnExit(WebRtcClientState::Connected), Self::clear_client_stats);
+        }
+        if !self.is_headless {
+            app.add_systems(Update, (Self::toggle_overlay, Self::show_overlay).chain());
+        }
+    }
+}
+
+// Connection quality as seen by renet, plus what's piling up in the data channels
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub rtt_ms: f64,
+    pub bytes_sent_per_sec: f64,
+    pub bytes_received_per_sec: f64,
+    pub packet_loss: f64, // 0 to 1
+    pub buffered_bytes: u32,
+    pub queued_packets: usize, // Held back by congestion
+    pub dropped_packets: u64 // Unreliable ones dropped under congestion
+}
+
+impl ConnectionStats {
+    fn new(info: NetworkInfo, channels: Option<&DataChannels>) -> Self {
+        ConnectionStats {
+            rtt_ms: info.rtt * 1000.,
+            bytes_sent_per_sec: info.bytes_sent_per_second,
+            bytes_received_per_sec: info.bytes_received_per_second,
+            packet_loss: info.packet_loss,
+            buffered_bytes: channels.map_or(0, |c| c.buffered_amount()),
+            queued_packets: channels.map_or(0, |c| c.queued_packets()),
+            dropped_packets: channels.map_or(0, |c| c.dropped_packets())
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct NetworkStats {
+    pub server: Option<ConnectionStats>, // Our connection to the server, when we're a client
+    pub clients: BTreeMap<ClientId, ConnectionStats>, // Connected clients, when we're hosting
+    pub visible: bool
+}
+
+impl NetworkStatsPlugin {
+    fn collect_server_stats(
+        rtc_server: Option<NonSend<WebRtcServer>>,
+        renet_server: Res<RenetServer>,
+        mut stats: ResMut<NetworkStats>
+    ) {
+        let Some(rtc_server) = rtc_server else {
+            return;
+        };
+        let channels = rtc_server.clients();
+        stats.clients = renet_server.clients_id().into_iter().filter_map(|client_id| {
+            let info = renet_server.network_info(client_id).ok()?;
+            let connection = rtc_server.connection(client_id).and_then(|c| channels.get(&c));
+            Some((client_id, ConnectionStats::new(info, connection)))
+        }).collect();
+    }
+
+    fn clear_server_stats(mut stats: ResMut<NetworkStats>) {
+        stats.clients.clear();
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn collect_client_stats(
+        rtc_client: NonSend<WebRtcClient>,
+        renet_client: Res<RenetClient>,
+        mut stats: ResMut<NetworkStats>
+    ) {
+        let channels = rtc_client.channel();
+        stats.server = Some(ConnectionStats::new(renet_client.network_info(), channels.as_ref()));
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn clear_client_stats(mut stats: ResMut<NetworkStats>) {
+        stats.server = None;
+    }
+
+    fn toggle_overlay(keys: Res<Input<KeyCode>>, mut stats: ResMut<NetworkStats>) {
+        if keys.just_pressed(KeyCode::F3) {
+            stats.visible = !stats.visible;
+        }
+    }
+
+    fn show_overlay(mut contexts: EguiContexts, stats: Res<NetworkStats>) {
+        if !stats.visible {
+            return;
+        }
+        if let Some(server) = &stats.server {
+            egui::Area::new("network_stats").anchor(egui::Align2::RIGHT_TOP, [-8., 8.]).show(contexts.ctx_mut(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    egui::Grid::new("network_stats_grid").show(ui, |ui| {
+                        ui.label("RTT");
+                        ui.label(format!("{:.0} ms", server.rtt_ms));
+                        ui.end_row();
+                        ui.label("Up");
+                        ui.label(format_rate(server.bytes_sent_per_sec));
+                        ui.end_row();
+                        ui.label("Down");
+                        ui.label(format_rate(server.bytes_received_per_sec));
+                        ui.end_row();
+                        ui.label("Loss");
+                        ui.label(format!("{:.1}%", server.packet_loss * 100.));
+                        ui.end_row();
+                        ui.label("Buffered");
+                        ui.label(format!("{} B", server.buffered_bytes));
+                        ui.end_row();
+                        ui.label("Queued");
+                        ui.label(server.queued_packets.to_string());
+                        ui.end_row();
+                        ui.label("Dropped");
+                        ui.label(server.dropped_packets.to_string());
+                        ui.end_row();
+                    });
+                });
+            });
+        }
+        if !stats.clients.is_empty() {
+            egui::Window::new("Clients").default_pos([8., 8.]).resizable(false).show(contexts.ctx_mut(), |ui| {
+                egui::Grid::new("client_stats_grid").striped(true).show(ui, |ui| {
+                    for header in ["Client", "RTT", "Up", "Down", "Loss", "Buffered", "Queued", "Dropped"] {
+                        ui.strong(header);
+                    }
+                    ui.end_row();
+                    for (client_id, client) in stats.clients.iter() {
+                        ui.label(client_id.to_string());
+                        ui.label(format!("{:.0} ms", client.rtt_ms));
+                        ui.label(format_rate(client.bytes_sent_per_sec));
+                        ui.label(format_rate(client.bytes_received_per_sec));
+                        ui.label(format!("{:.1}%", client.packet_loss * 100.));
+                        ui.label(format!("{} B", client.buffered_bytes));
+                        ui.label(client.queued_packets.to_string());
+                        ui.label(client.dropped_packets.to_string());
+                        ui.end_row();
+                    }
+                });
+            });
+        }
+    }
+}
+
+fn format_rate(bytes_per_sec: f64) -> String {
+    if bytes_per_sec >= 1024. {
+        format!("{:.1} KiB/s", bytes_per_sec / 1024.)
+    } else {
+        format!("{:.0} B/s", bytes_per_sec)
+    }
+}
@@ -1,3 +1,5 @@
+use std::{future::{poll_fn, Future}, pin::pin, task::Poll};
+
 use js_sys::Function;
 use wasm_bindgen::prelude::*;
 
@@ -5,3 +7,74 @@ use wasm_bindgen::prelude::*;
 extern "C" {
     pub fn setTimeout(f: Function, t: u32);
 }
+
+// Labels of the data channels negotiated with every peer
+pub const RELIABLE_CHANNEL_LABEL: &str = "reliable";
+pub const UNRELIABLE_CHANNEL_LABEL: &str = "unreliable";
+// How long a refused peer is kept around, so the refusal reaches it before the connection closes
+pub const HANDSHAKE_LINGER_MS: u32 = 1000;
+
+// renet starts every packet with its type; 1 and 3 are the small and sliced packets of unreliable channels
+pub fn is_unreliable_packet(packet: &[u8]) -> bool {
+    matches!(packet.first(), Some(1) | Some(3))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn spawn<F: Future<Output = ()> + 'static>(future: F) {
+    wasm_bindgen_futures::spawn_local(future);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+    super::native::runtime().spawn(future);
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(ms: u32) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| setTimeout(resolve, ms));
+    wasm_bindgen_futures::JsFuture::from(promise).await.unwrap();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(ms: u32) {
+    tokio::time::sleep(std::time::Duration::from_millis(ms.into())).await;
+}
+
+// Gives up on `future` after `ms`, naming what we waited for in the error
+pub async fn timeout<F: Future>(ms: u32, what: &str, future: F) -> Result<F::Output, String> {
+    let mut future = pin!(future);
+    let mut deadline = pin!(sleep(ms));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        deadline.as_mut().poll(cx).map(|_| Err(format!("Timed out after {}ms waiting for {}", ms, what)))
+    }).await
+}
+
+// Exponential backoff between reconnection attempts
+pub struct Backoff {
+    delay_ms: u32
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { delay_ms: Self::MIN_DELAY_MS }
+    }
+}
+
+impl Backoff {
+    const MIN_DELAY_MS: u32 = 1000;
+    const MAX_DELAY_MS: u32 = 30000;
+
+    // Current delay, doubling it for next time
+    pub fn next_delay_ms(&mut self) -> u32 {
+        let delay_ms = self.delay_ms;
+        self.delay_ms = (self.delay_ms * 2).min(Self::MAX_DELAY_MS);
+        delay_ms
+    }
+
+    pub async fn wait(&mut self) {
+        sleep(self.next_delay_ms()).await;
+    }
+}
@@ -1,13 +1,13 @@
-use std::{collections::{HashMap, VecDeque}, rc::Rc, cell::RefCell, ops::DerefMut};
+use std::{collections::{HashMap, VecDeque}, rc::Rc, cell::{Cell, RefCell}, ops::DerefMut};
 use core::ops::Deref;
 
 use js_sys::{Reflect, Promise, Array, Object};
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::{JsFuture, spawn_local};
-use web_sys::{WebSocket, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSessionDescriptionInit, RtcSdpType, RtcIceCandidateInit, RtcIceCandidate, RtcDataChannelEvent, RtcConfiguration};
+use web_sys::{WebSocket, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSessionDescriptionInit, RtcSdpType, RtcIceCandidateInit, RtcIceCandidate, RtcDataChannelEvent, RtcDataChannelInit, RtcConfiguration, RtcIceConnectionState, RtcOfferOptions};
 use wasm_bindgen::prelude::*;
 
-use super::{callback_channel::SendRecvCallbackChannel, signaling::{ServerEntry, ConnectionId, SignalingMessage, RelayMessage, SignalingDemux, SignalingDemuxRecv, SignalingClientConnection}};
+use super::{callback_channel::{DataChannels, SendRecvCallbackChannel}, deque_channel::{JsDequeChannel, JsReceiver, JsSender}, config::{IceServer, NetworkConfig, PROTOCOL_VERSION}, signaling::{ServerEntry, ConnectionId, HandshakeMessage, SignalingMessage, RelayMessage, SignalingDemux, SignalingDemuxRecv, SignalingClientConnection}, util::{sleep, timeout, Backoff, HANDSHAKE_LINGER_MS, RELIABLE_CHANNEL_LABEL, UNRELIABLE_CHANNEL_LABEL}};
 
 macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
@@ -24,60 +24,134 @@ extern "C" {
     fn warn(s: &str);
 }
 
-fn make_rtc_peer() -> Result<RtcPeerConnection, JsValue> {
-    let stun_servers = [
-        "stun1.l.google.com:19302",
-        "stun2.l.google.com:19302",
-        "stun3.l.google.com:19302",
-        "stun4.l.google.com:19302",
-    ];
+fn make_rtc_peer(ice_servers: &[IceServer]) -> Result<RtcPeerConnection, JsValue> {
     let mut config = RtcConfiguration::new();
-    let c = config.ice_servers(&Array::from_iter(stun_servers.iter().map(|str| {
+    let c = config.ice_servers(&Array::from_iter(ice_servers.iter().map(|server| {
         let o = Object::new();
-        Reflect::set(&o, &JsValue::from_str("urls"), &Object::from(JsValue::from_str(&format!("stun:{}", str)))).unwrap();
+        Reflect::set(&o, &JsValue::from_str("urls"), &JsValue::from_str(&server.url)).unwrap();
+        if let (Some(username), Some(credential)) = (&server.username, &server.credential) {
+            Reflect::set(&o, &JsValue::from_str("username"), &JsValue::from_str(username)).unwrap();
+            Reflect::set(&o, &JsValue::from_str("credential"), &JsValue::from_str(credential)).unwrap();
+        }
         o
     })));
 
     RtcPeerConnection::new_with_configuration(c)
 }
 
+// The hub may be shared with other games, whose servers we can't join
+fn of_game(game: &str, mut servers: HashMap<ConnectionId, ServerEntry>) -> HashMap<ConnectionId, ServerEntry> {
+    servers.retain(|_, entry| entry.game == game);
+    servers
+}
+
+#[derive(Clone)]
 pub struct AsyncWebRtcBrowser {
     websocket: SendRecvCallbackChannel,
-    servers: HashMap<ConnectionId, ServerEntry>,
+    servers: Rc<RefCell<HashMap<ConnectionId, ServerEntry>>>,
+    lists: Rc<Cell<u32>>, // How many lists arrived, telling whether a refresh was answered
+    relays: JsReceiver<SignalingMessage>, // Relayed messages, everything else is handled by listen()
+    ice_servers: Vec<IceServer>,
+    negotiation_timeout_ms: u32,
+    ice_restart_timeout_ms: u32
 }
 
 impl AsyncWebRtcBrowser {
-    pub async fn new(signaling_url: &str) -> Result<AsyncWebRtcBrowser, JsValue> {
-        let websocket = WebSocket::new(signaling_url)?;
+    pub async fn new(config: &NetworkConfig) -> Result<AsyncWebRtcBrowser, JsValue> {
+        let websocket = WebSocket::new(&config.signaling_url)?;
         let mut ws = SendRecvCallbackChannel::new(Box::new(websocket)).await?;
         // console_log!("Browser: ws connected");
         let msg: SignalingMessage = ws.recv().await?;
         let servers = if let SignalingMessage::List { servers } = msg {
             // console_log!("Browser: List msg received");
-            servers
+            of_game(&config.game_name, servers)
         } else {
             return Err(JsValue::from_str(&format!("Unexpected msg {:?}", msg)));
         };
-        Ok(AsyncWebRtcBrowser { websocket: ws, servers })
+        let (relay_sender, relays) = JsDequeChannel::channel();
+        let browser = AsyncWebRtcBrowser { websocket: ws, servers: Rc::new(RefCell::new(servers)), lists: Rc::new(Cell::new(1)), relays, ice_servers: config.ice_servers.clone(), negotiation_timeout_ms: config.negotiation_timeout_ms, ice_restart_timeout_ms: config.ice_restart_timeout_ms };
+        spawn_local(Self::listen(browser.websocket.clone(), browser.servers.clone(), browser.lists.clone(), relay_sender, config.game_name.clone()));
+        Ok(browser)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &ServerEntry)> {
-        self.servers.iter()
+    // Keep the server list up to date, passing relayed messages on to connect()
+    async fn listen(
+        mut websocket: SendRecvCallbackChannel,
+        servers: Rc<RefCell<HashMap<ConnectionId, ServerEntry>>>,
+        lists: Rc<Cell<u32>>,
+        relays: JsSender<SignalingMessage>,
+        game: String
+    ) {
+        loop {
+            match websocket.recv().await {
+                Ok(SignalingMessage::List { servers: list }) => {
+                    *servers.borrow_mut() = of_game(&game, list);
+                    lists.set(lists.get() + 1);
+                }
+                Ok(msg @ SignalingMessage::Relay { .. }) => {
+                    if let Err(e) = relays.send(msg) {
+                        console_warn!("Browser: Dropped relayed msg: {:?}", e);
+                    }
+                }
+                Ok(msg) => console_warn!("Browser: Unexpected msg {:?}", msg),
+                Err(e) => {
+                    console_warn!("Browser: Lost signaling server: {:?}", e);
+                    if let Err(e) = relays.close() {
+                        console_warn!("Browser: Failed to close relays: {:?}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    // Ask for the current server list, which listen() picks up once it arrives
+    pub fn refresh(&mut self) -> Result<(), JsValue> {
+        self.websocket.send(SignalingMessage::Refresh)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.websocket.is_closed()
+    }
+
+    pub fn lists_received(&self) -> u32 {
+        self.lists.get()
+    }
+
+    pub fn close(&self) {
+        self.websocket.close();
     }
 
     pub fn servers(&self) -> HashMap<ConnectionId, ServerEntry> {
-        self.servers.clone()
+        self.servers.borrow().clone()
     }
 
-    pub async fn connect(mut self, server_id: ConnectionId) -> Result<AsyncWebRtcClient, JsValue> {
-        if !self.servers.contains_key(&server_id) {
-            return Err(JsValue::from_str(&format!("Invalid server id {}", server_id)));
+    // Negotiate with a server, giving up if any step stalls
+    // `password` is presented to the server, which refuses us without the right one
+    pub async fn connect(self, server_id: ConnectionId, password: Option<String>) -> Result<AsyncWebRtcClient, ConnectError> {
+        if !self.servers.borrow().contains_key(&server_id) {
+            return Err(JsValue::from_str(&format!("Invalid server id {}", server_id)).into());
+        }
+
+        let peer = make_rtc_peer(&self.ice_servers)?;
+        let websocket = self.websocket.clone();
+        let result = self.negotiate(server_id, peer.clone(), password).await;
+        if result.is_err() {
+            // Closing the websocket closes the relays too, ending the ICE candidate task
+            peer.close();
+            websocket.close();
         }
+        result
+    }
 
-        let peer = make_rtc_peer()?;
+    async fn negotiate(mut self, server_id: ConnectionId, peer: RtcPeerConnection, password: Option<String>) -> Result<AsyncWebRtcClient, ConnectError> {
+        let timeout_ms = self.negotiation_timeout_ms;
 
-        // Create data channel
-        let data = peer.create_data_channel("my-data-channel");
+        // Create data channels: renet does its own resending, so the unreliable one never retransmits or reorders
+        let reliable = peer.create_data_channel(RELIABLE_CHANNEL_LABEL);
+        let mut unreliable_init = RtcDataChannelInit::new();
+        unreliable_init.ordered(false).max_retransmits(0);
+        let unreliable = peer.create_data_channel_with_data_channel_dict(UNRELIABLE_CHANNEL_LABEL, &unreliable_init);
 
         // Start sending ICE candidates to server
         let mut ws_cloned = self.websocket.clone();
@@ -94,7 +168,9 @@ impl AsyncWebRtcBrowser {
                             sdp_m_line_index: candidate.sdp_m_line_index(),
                         }
                     };
-                    ws_cloned.send(msg).unwrap();
+                    if let Err(e) = ws_cloned.send(msg) {
+                        console_warn!("Browser: Failed to send ICE candidate: {:?}", e);
+                    }
                 }
             });
         peer.set_onicecandidate(Some(onicecandidate_callback1.as_ref().unchecked_ref()));
@@ -104,7 +180,7 @@ impl AsyncWebRtcBrowser {
         let offer = JsFuture::from(peer.create_offer()).await?;
         let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))?
             .as_string()
-            .unwrap();
+            .ok_or_else(|| JsValue::from_str("Offer without sdp"))?;
 
         let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
         offer_obj.sdp(&offer_sdp);
@@ -116,14 +192,14 @@ impl AsyncWebRtcBrowser {
             dst: server_id.to_owned(),
             data: RelayMessage::Offer(offer_sdp)
         };
-        self.websocket.send(msg).unwrap();
+        self.websocket.send(msg)?;
 
         // Receive ANSWER from server
-        let msg: SignalingMessage = self.websocket.recv().await?;
+        let msg = timeout(timeout_ms, "answer", self.relays.recv()).await.map_err(|e| JsValue::from_str(&e))??;
         let answer_sdp = if let SignalingMessage::Relay { data: RelayMessage::Answer(answer_sdp), .. } = msg {
             answer_sdp
         } else {
-            return Err(JsValue::from_str(&format!("Unexpected msg: {:?}", msg)));
+            return Err(JsValue::from_str(&format!("Unexpected msg: {:?}", msg)).into());
         };
 
         let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
@@ -131,86 +207,300 @@ impl AsyncWebRtcBrowser {
         let srd_promise = peer.set_remote_description(&answer_obj);
         JsFuture::from(srd_promise).await?;
 
-        // Recv ICE candidates from server
-        let mut ws_cloned = self.websocket.clone();
+        // Recv ICE candidates, and later answers to ICE restarts, from server
+        let relays = self.relays.clone();
         let pc1_clone = peer.clone();
         spawn_local(async move { // IMPORTANT: After this point, only this task may recv().
-            loop {
-                let msg: SignalingMessage = ws_cloned.recv().await.unwrap();
-                if let SignalingMessage::Relay { data: RelayMessage::IceCandidate { candidate, sdp_mid, sdp_m_line_index }, .. } = msg {
-                    let mut init = RtcIceCandidateInit::new(&candidate);
-                    let sdp_mid = match &sdp_mid {
-                        Some(str) => Some(str.as_str()),
-                        None => None
-                    };
-                    init.sdp_mid(sdp_mid);
-                    init.sdp_m_line_index(sdp_m_line_index);
-                    let cand = RtcIceCandidate::new(&init).unwrap();
-                    JsFuture::from(pc1_clone.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&cand))).await.unwrap();
-                } else {
-                    console_warn!("Recv unexpected: {:?}", msg);
+            while let Ok(msg) = relays.recv().await {
+                match msg {
+                    SignalingMessage::Relay { data: RelayMessage::IceCandidate { candidate, sdp_mid, sdp_m_line_index }, .. } => {
+                        let mut init = RtcIceCandidateInit::new(&candidate);
+                        let sdp_mid = match &sdp_mid {
+                            Some(str) => Some(str.as_str()),
+                            None => None
+                        };
+                        init.sdp_mid(sdp_mid);
+                        init.sdp_m_line_index(sdp_m_line_index);
+                        let added = match RtcIceCandidate::new(&init) {
+                            Ok(cand) => JsFuture::from(pc1_clone.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&cand))).await.map(|_| ()),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = added {
+                            console_warn!("Browser: Failed to add ICE candidate: {:?}", e);
+                        }
+                    }
+                    SignalingMessage::Relay { data: RelayMessage::Answer(answer_sdp), .. } => {
+                        let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+                        answer_obj.sdp(&answer_sdp);
+                        if let Err(e) = JsFuture::from(pc1_clone.set_remote_description(&answer_obj)).await {
+                            console_warn!("Failed to apply ICE restart answer: {:?}", e);
+                        }
+                    }
+                    msg => console_warn!("Recv unexpected: {:?}", msg),
                 }
             }
         });
 
-        Ok(AsyncWebRtcClient {
+        let (mut reliable, unreliable) = timeout(timeout_ms, "data channels", async {
+            Ok::<_, JsValue>((
+                SendRecvCallbackChannel::new(Box::new(reliable)).await?,
+                SendRecvCallbackChannel::new(Box::new(unreliable)).await?
+            ))
+        }).await.map_err(|e| JsValue::from_str(&e))??;
+
+        // Make sure we speak the same protocol and may join before handing the channels to renet
+        reliable.send(HandshakeMessage::Hello { version: PROTOCOL_VERSION, password })?;
+        let reply = timeout(timeout_ms, "handshake", reliable.recv()).await.map_err(|e| JsValue::from_str(&e));
+        let reply = match reply.and_then(|reply| reply) {
+            Ok(reply) => reply,
+            Err(e) => {
+                reliable.close();
+                unreliable.close();
+                return Err(e.into());
+            }
+        };
+        match reply {
+            HandshakeMessage::Welcome => {}
+            HandshakeMessage::Incompatible { version } => {
+                reliable.close();
+                unreliable.close();
+                return Err(ConnectError::Incompatible { server_version: version });
+            }
+            HandshakeMessage::WrongPassword => {
+                reliable.close();
+                unreliable.close();
+                return Err(ConnectError::WrongPassword);
+            }
+            msg => return Err(JsValue::from_str(&format!("Unexpected handshake message: {:?}", msg)).into()),
+        }
+
+        let client = AsyncWebRtcClient {
             _connection: peer,
-            channel: SendRecvCallbackChannel::new(Box::new(data)).await.unwrap()
-        })
+            channels: DataChannels::new(reliable, unreliable),
+            restarting: Rc::new(Cell::new(false))
+        };
+        client.monitor_ice(self.websocket.clone(), server_id, self.ice_restart_timeout_ms);
+        Ok(client)
+    }
+}
+
+pub enum ConnectError {
+    Incompatible { server_version: u32 }, // Retrying won't help
+    WrongPassword, // Neither will this, without another password
+    Failed(JsValue)
+}
+
+impl From<JsValue> for ConnectError {
+    fn from(e: JsValue) -> Self {
+        ConnectError::Failed(e)
     }
 }
 
 pub struct AsyncWebRtcClient {
     _connection: RtcPeerConnection,
-    channel: SendRecvCallbackChannel
+    channels: DataChannels,
+    restarting: Rc<Cell<bool>> // Until the connection is back, or we gave up on it
+}
+
+impl AsyncWebRtcClient {
+    pub fn is_restarting(&self) -> bool {
+        self.restarting.get()
+    }
+
+    // Restart ICE whenever the connection drops. The signaling relay stays open for this once connected.
+    fn monitor_ice(&self, websocket: SendRecvCallbackChannel, server_id: ConnectionId, restart_timeout_ms: u32) {
+        let peer = self._connection.clone();
+        let channels = self.channels.clone();
+        let restarting = self.restarting.clone();
+        let attempt = Rc::new(Cell::new(0u32));
+        let oniceconnectionstatechange_callback = Closure::<dyn FnMut()>::new(move || {
+            let state = peer.ice_connection_state();
+            console_log!("Client: ICE connection state changed to {:?}", state);
+            match state {
+                RtcIceConnectionState::Disconnected | RtcIceConnectionState::Failed if !restarting.get() => {
+                    restarting.set(true);
+                    attempt.set(attempt.get() + 1);
+                    let started = attempt.get();
+                    let (peer, websocket, server_id, channels, restarting, attempt) = (peer.clone(), websocket.clone(), server_id.clone(), channels.clone(), restarting.clone(), attempt.clone());
+                    spawn_local(async move {
+                        console_warn!("Client: Connection interrupted, restarting ICE...");
+                        // The browser may still recover by itself, e.g. after a brief network hiccup
+                        if let Err(e) = Self::restart_ice(&peer, websocket, server_id).await {
+                            console_warn!("Client: Failed to restart ICE: {:?}", e);
+                        }
+                        sleep(restart_timeout_ms).await;
+                        // Only the latest attempt may give up, and only if the connection hasn't recovered meanwhile
+                        if attempt.get() == started && restarting.get() {
+                            console_warn!("Client: Connection didn't recover within {}ms", restart_timeout_ms);
+                            restarting.set(false);
+                            channels.close();
+                        }
+                    });
+                }
+                RtcIceConnectionState::Connected | RtcIceConnectionState::Completed if restarting.get() => {
+                    console_log!("Client: Connection recovered");
+                    restarting.set(false);
+                }
+                _ => {}
+            }
+        });
+        self._connection.set_oniceconnectionstatechange(Some(oniceconnectionstatechange_callback.as_ref().unchecked_ref()));
+        oniceconnectionstatechange_callback.forget();
+    }
+
+    // Re-offer with fresh ICE credentials over the signaling relay
+    async fn restart_ice(peer: &RtcPeerConnection, mut websocket: SendRecvCallbackChannel, server_id: ConnectionId) -> Result<(), JsValue> {
+        let mut options = RtcOfferOptions::new();
+        options.ice_restart(true);
+        let offer = JsFuture::from(peer.create_offer_with_rtc_offer_options(&options)).await?;
+        let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))?.as_string().unwrap_or_default();
+        let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+        offer_obj.sdp(&offer_sdp);
+        JsFuture::from(peer.set_local_description(&offer_obj)).await?;
+        websocket.send(SignalingMessage::Relay {
+            src: "".to_owned(),
+            dst: server_id,
+            data: RelayMessage::Offer(offer_sdp)
+        })
+    }
 }
 
 impl Deref for AsyncWebRtcClient {
-    type Target = SendRecvCallbackChannel;
+    type Target = DataChannels;
 
     fn deref(&self) -> &Self::Target {
-        &self.channel
+        &self.channels
     }
 }
 
 impl DerefMut for AsyncWebRtcClient {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.channel
+        &mut self.channels
     }
 }
 
 #[derive(Clone)]
 pub struct AsyncWebRtcServer {
-    signaling: SendRecvCallbackChannel,
-    pub clients: Rc<RefCell<HashMap<ConnectionId, SendRecvCallbackChannel>>>,
-    pub new_clients: Rc<RefCell<VecDeque<ConnectionId>>>
+    config: Rc<NetworkConfig>,
+    entry: Rc<RefCell<ServerEntry>>,
+    signaling: Rc<RefCell<Option<SendRecvCallbackChannel>>>, // None while re-registering after losing the signaling server
+    clients: Rc<RefCell<HashMap<ConnectionId, DataChannels>>>,
+    new_clients: Rc<RefCell<VecDeque<ConnectionId>>>,
+    closed: Rc<Cell<bool>> // Unregistered for good, don't re-register
 }
 
 impl AsyncWebRtcServer {
-    pub async fn new(signaling_url: &str, game_name: &str, server_name: &str) -> Result<AsyncWebRtcServer, JsValue> {
+    pub async fn new(config: &NetworkConfig) -> Result<AsyncWebRtcServer, JsValue> {
+        let entry = config.server_entry();
+        let ws = Self::register(config, &entry).await?;
+
+        let server = AsyncWebRtcServer {
+            config: Rc::new(config.clone()),
+            entry: Rc::new(RefCell::new(entry)),
+            signaling: Rc::new(RefCell::new(Some(ws.clone()))),
+            clients: Rc::new(RefCell::new(HashMap::new())),
+            new_clients: Rc::new(RefCell::new(VecDeque::new())),
+            closed: Rc::new(Cell::new(false))
+        };
+
+        spawn_local(Self::listen(server.clone(), ws));
+
+        Ok(server)
+    }
+
+    async fn register(config: &NetworkConfig, entry: &ServerEntry) -> Result<SendRecvCallbackChannel, JsValue> {
         // Register as a server
-        let websocket = WebSocket::new(signaling_url)?;
+        let websocket = WebSocket::new(&config.signaling_url)?;
         let mut ws = SendRecvCallbackChannel::new(Box::new(websocket)).await?;
-        ws.send(SignalingMessage::Register { game: game_name.to_owned(), name: server_name.to_owned() })?;
+        ws.send(SignalingMessage::Register(entry.clone()))?;
 
         // Discard list of existing servers
         let _: SignalingMessage = ws.recv().await?;
 
-        let server = AsyncWebRtcServer {
-            signaling: ws.clone(),
-            clients: Rc::new(RefCell::new(HashMap::new())),
-            new_clients: Rc::new(RefCell::new(VecDeque::new()))
-        };
+        Ok(ws)
+    }
 
-        spawn_local(Self::listen(server.clone()));
+    pub fn is_registered(&self) -> bool {
+        self.signaling.borrow().is_some()
+    }
 
-        Ok(server)
+    // Change what the server browser shows about us, re-registering if anything changed
+    pub fn update_entry(&self, update: impl FnOnce(&mut ServerEntry)) {
+        let mut entry = self.entry.borrow_mut();
+        let previous = entry.clone();
+        update(&mut entry);
+        if *entry == previous {
+            return;
+        }
+        // While re-registering, the new entry is sent once we're back
+        if let Some(signaling) = self.signaling.borrow_mut().as_mut() {
+            if let Err(e) = signaling.send(SignalingMessage::Register(entry.clone())) {
+                console_warn!("WebRtcServer: Failed to update server entry: {:?}", e);
+            }
+        }
+    }
+
+    // Leave the server list, existing clients stay connected
+    pub fn unregister(&self) {
+        self.closed.set(true);
+        if let Some(mut signaling) = self.signaling.borrow_mut().take() {
+            if let Err(e) = signaling.send(SignalingMessage::Unregister) {
+                console_warn!("WebRtcServer: Failed to unregister: {:?}", e);
+            }
+            signaling.close();
+        }
+    }
+
+    pub fn close(&self) {
+        self.unregister();
+        for (_, channels) in self.clients.borrow_mut().drain() {
+            channels.close();
+        }
+    }
+
+    pub fn clients(&self) -> HashMap<ConnectionId, DataChannels> {
+        self.clients.borrow().clone()
+    }
+
+    pub fn remove_client(&self, connection: &str) {
+        self.clients.borrow_mut().remove(connection);
+    }
+
+    pub fn new_clients(&self) -> Vec<ConnectionId> {
+        self.new_clients.borrow_mut().drain(..).collect()
+    }
+
+    // Accept connections until the signaling server goes away, then re-register and repeat.
+    // Established clients don't need signaling anymore, so they stay connected meanwhile.
+    async fn listen(server: Self, mut signaling: SendRecvCallbackChannel) {
+        loop {
+            Self::accept_connections(&server, signaling).await;
+            *server.signaling.borrow_mut() = None;
+
+            let mut backoff = Backoff::default();
+            signaling = loop {
+                if server.closed.get() {
+                    return;
+                }
+                backoff.wait().await;
+                console_log!("WebRtcServer: Re-registering with {}", server.config.signaling_url);
+                let entry = server.entry.borrow().clone();
+                match Self::register(&server.config, &entry).await {
+                    Ok(ws) => break ws,
+                    Err(e) => console_warn!("WebRtcServer: Failed to re-register: {:?}", e),
+                }
+            };
+            if server.closed.get() {
+                signaling.close();
+                return;
+            }
+            *server.signaling.borrow_mut() = Some(signaling.clone());
+            console_log!("WebRtcServer: Re-registered");
+        }
     }
 
-    async fn listen(server: Self) {
-        // Listen for incoming connections
-        let signaling_server = server.signaling.clone();
-        let mut demux = SignalingDemux::new(signaling_server);
+    async fn accept_connections(server: &Self, signaling: SendRecvCallbackChannel) {
+        let mut demux = SignalingDemux::new(signaling);
         loop {
             match demux.recv().await {
                 Ok(SignalingDemuxRecv::System(msg)) => {
@@ -220,33 +510,51 @@ impl AsyncWebRtcServer {
                     console_log!("WebRtcServer: Handling new connection...");
                     let server = server.clone();
                     spawn_local(async move {
-                        let channel = Self::handle_connection(client_conn).await.unwrap();
-                        server.clients.borrow_mut().insert(connection_id.clone(), channel);
-                        server.new_clients.borrow_mut().push_back(connection_id.clone());
-                        console_log!("WebRtcServer: Added connection {}", connection_id);
+                        match Self::handle_connection(client_conn, &server.config).await {
+                            Ok(channels) => {
+                                server.clients.borrow_mut().insert(connection_id.clone(), channels);
+                                server.new_clients.borrow_mut().push_back(connection_id.clone());
+                                console_log!("WebRtcServer: Added connection {}", connection_id);
+                            }
+                            Err(e) => console_warn!("WebRtcServer: Failed to establish connection {}: {:?}", connection_id, e),
+                        }
                     })
                 }
+                Err(_) if server.closed.get() => return,
                 Err(e) => {
-                    console_warn!("ERROR: WebRtcServer.listen(): {:?}. Stopping listening for new connections", e);
-                    // TODO if failed, can we reconnect/reregister silently to avoid losing existing clients?
+                    console_warn!("ERROR: WebRtcServer.listen(): {:?}. Lost signaling server", e);
                     return;
                 },
             }
         }
     }
 
-    async fn handle_connection(mut client_conn: SignalingClientConnection) -> Result<SendRecvCallbackChannel, JsValue> {
+    // Negotiate with a new client, giving up on it if any step stalls
+    async fn handle_connection(client_conn: SignalingClientConnection, config: &NetworkConfig) -> Result<DataChannels, JsValue> {
         console_log!("\t\tWebRtcServer.handle_connection(): Started");
+        let closer = client_conn.closer();
+        let peer = make_rtc_peer(&config.ice_servers)?;
+        let result = Self::negotiate(client_conn, peer.clone(), config.negotiation_timeout_ms, &config.password).await;
+        match &result {
+            Ok(channels) => Self::monitor_ice(&peer, channels.clone(), config.ice_restart_timeout_ms),
+            Err(_) => {
+                // Ends the ICE candidate task, whose connection then leaves the demux
+                closer.close();
+                peer.close();
+            }
+        }
+        result
+    }
 
+    async fn negotiate(mut client_conn: SignalingClientConnection, peer: RtcPeerConnection, timeout_ms: u32, password: &Option<String>) -> Result<DataChannels, JsValue> {
         // Receive OFFER from client
-        let msg = client_conn.recv().await?;
+        let msg = timeout(timeout_ms, "offer", client_conn.recv()).await.map_err(|e| JsValue::from_str(&e))??;
         let offer_sdp = if let RelayMessage::Offer(offer_sdp) = msg {
             console_log!("\t\tWebRtcServer.handle_connection(): Received OFFER");
             offer_sdp
         } else {
             return Err(JsValue::from_str(&format!("WebRtcServer.handle_connection(): Unexpected msg from signaling server: {:?}", msg)));
         };
-        let peer = make_rtc_peer()?;
         let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
         offer_obj.sdp(&offer_sdp);
         JsFuture::from(peer.set_remote_description(&offer_obj)).await?;
@@ -256,11 +564,14 @@ impl AsyncWebRtcServer {
         let onicecandidate_callback2 =
             Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| {
                 if let Some(candidate) = ev.candidate() {
-                    client_sender.send(RelayMessage::IceCandidate {
+                    let sent = client_sender.send(RelayMessage::IceCandidate {
                         candidate: candidate.candidate(),
                         sdp_mid: candidate.sdp_mid(),
                         sdp_m_line_index: candidate.sdp_m_line_index()
-                    }).unwrap();
+                    });
+                    if let Err(e) = sent {
+                        console_warn!("WebRtcServer.handle_connection(): Failed to send ICE candidate: {:?}", e);
+                    }
                 }
             });
         peer.set_onicecandidate(Some(onicecandidate_callback2.as_ref().unchecked_ref()));
@@ -268,7 +579,7 @@ impl AsyncWebRtcServer {
 
         // Send ANSWER to peer_1
         let answer = JsFuture::from(peer.create_answer()).await?;
-        let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))?.as_string().unwrap();
+        let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))?.as_string().ok_or_else(|| JsValue::from_str("Answer without sdp"))?;
 
         let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
         answer_obj.sdp(&answer_sdp);
@@ -277,46 +588,142 @@ impl AsyncWebRtcServer {
 
         client_conn.send(RelayMessage::Answer(answer_sdp))?;
 
-        // Recv ICE candidates from client
+        // Recv ICE candidates, and later ICE restart offers, from client
         // Takes ownership of client conn
         let peer_clone = peer.clone();
         spawn_local(async move {
-            loop {
-                let msg = client_conn.recv().await.unwrap();
-                if let RelayMessage::IceCandidate { candidate, sdp_mid, sdp_m_line_index } = msg {
-                    let mut init = RtcIceCandidateInit::new(&candidate);
-                    let sdp_mid = match &sdp_mid {
-                        Some(str) => Some(str.as_str()),
-                        None => None
-                    };
-                    init.sdp_mid(sdp_mid);
-                    init.sdp_m_line_index(sdp_m_line_index);
-                    let cand = RtcIceCandidate::new(&init).unwrap();
-                    JsFuture::from(peer_clone.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&cand))).await.unwrap();
-                } else {
-                    console_warn!("WebRtcServer.handle_connection(): Recv unexpected while listening for ICE candidates: {:?}", msg);
+            while let Ok(msg) = client_conn.recv().await {
+                match msg {
+                    RelayMessage::IceCandidate { candidate, sdp_mid, sdp_m_line_index } => {
+                        let mut init = RtcIceCandidateInit::new(&candidate);
+                        let sdp_mid = match &sdp_mid {
+                            Some(str) => Some(str.as_str()),
+                            None => None
+                        };
+                        init.sdp_mid(sdp_mid);
+                        init.sdp_m_line_index(sdp_m_line_index);
+                        let added = match RtcIceCandidate::new(&init) {
+                            Ok(cand) => JsFuture::from(peer_clone.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&cand))).await.map(|_| ()),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = added {
+                            console_warn!("WebRtcServer.handle_connection(): Failed to add ICE candidate: {:?}", e);
+                        }
+                    }
+                    RelayMessage::Offer(offer_sdp) => {
+                        if let Err(e) = Self::restart_ice(&peer_clone, &mut client_conn, offer_sdp).await {
+                            console_warn!("WebRtcServer.handle_connection(): Failed to answer ICE restart: {:?}", e);
+                        }
+                    }
+                    msg => console_warn!("WebRtcServer.handle_connection(): Recv unexpected while listening for ICE candidates: {:?}", msg),
                 }
             }
         });
 
-        // Get data channel
+        // Get data channels, resolving once both are open
         let peer_clone = peer.clone();
-        let data_channel = Rc::new(RefCell::new(None));
-        JsFuture::from(Promise::new(&mut |resolve, _| {
-            let data_channel_cloned = data_channel.clone();
+        let data_channels = Rc::new(RefCell::new(HashMap::new()));
+        let data_channels_open = JsFuture::from(Promise::new(&mut |resolve, _| {
+            let data_channels_cloned = data_channels.clone();
             let ondatachannel_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcDataChannelEvent| {
                 let resolve = resolve.clone();
-                let data_channel = data_channel_cloned.clone();
+                let data_channels = data_channels_cloned.clone();
                 spawn_local(async move {
-                    *data_channel.borrow_mut() = Some(SendRecvCallbackChannel::new(Box::new(ev.channel())).await.unwrap());
-                    resolve.call0(&JsValue::UNDEFINED).unwrap();
+                    let label = ev.channel().label();
+                    let channel = match SendRecvCallbackChannel::new(Box::new(ev.channel())).await {
+                        Ok(channel) => channel,
+                        Err(e) => {
+                            console_warn!("WebRtcServer.handle_connection(): Data channel `{}` failed to open: {:?}", label, e);
+                            return;
+                        }
+                    };
+                    let mut data_channels = data_channels.borrow_mut();
+                    data_channels.insert(label, channel);
+                    if data_channels.contains_key(RELIABLE_CHANNEL_LABEL) && data_channels.contains_key(UNRELIABLE_CHANNEL_LABEL) {
+                        resolve.call0(&JsValue::UNDEFINED).unwrap();
+                    }
                 });
             });
             peer_clone.set_ondatachannel(Some(ondatachannel_callback.as_ref().unchecked_ref()));
             ondatachannel_callback.forget();
-        })).await?;
-        let data_channel = data_channel.borrow_mut().take().expect("WebRtcServer.handle_connection(): Expected data channel to be ready");
+        }));
+        timeout(timeout_ms, "data channels", data_channels_open).await.map_err(|e| JsValue::from_str(&e))??;
+        let (mut reliable, unreliable) = {
+            let mut data_channels = data_channels.borrow_mut();
+            (
+                data_channels.remove(RELIABLE_CHANNEL_LABEL).expect("WebRtcServer.handle_connection(): Expected reliable data channel to be ready"),
+                data_channels.remove(UNRELIABLE_CHANNEL_LABEL).expect("WebRtcServer.handle_connection(): Expected unreliable data channel to be ready")
+            )
+        };
+
+        let handshake = timeout(timeout_ms, "handshake", Self::handshake(&mut reliable, password)).await.map_err(|e| JsValue::from_str(&e));
+        if let Err(e) = handshake.and_then(|result| result) {
+            // Unlike natively, dropping the channels doesn't close them
+            reliable.close();
+            unreliable.close();
+            return Err(e);
+        }
+        Ok(DataChannels::new(reliable, unreliable))
+    }
+
+    // The client re-offers with fresh ICE credentials when its connection to us drops
+    async fn restart_ice(peer: &RtcPeerConnection, client_conn: &mut SignalingClientConnection, offer_sdp: String) -> Result<(), JsValue> {
+        console_log!("WebRtcServer: Client is restarting ICE");
+        let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+        offer_obj.sdp(&offer_sdp);
+        JsFuture::from(peer.set_remote_description(&offer_obj)).await?;
+        let answer = JsFuture::from(peer.create_answer()).await?;
+        let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))?.as_string().unwrap_or_default();
+        let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+        answer_obj.sdp(&answer_sdp);
+        JsFuture::from(peer.set_local_description(&answer_obj)).await?;
+        client_conn.send(RelayMessage::Answer(answer_sdp))
+    }
+
+    // Once the connection drops, give the client until the restart timeout to recover it.
+    // Closing its channels gets the client removed like any other.
+    fn monitor_ice(peer: &RtcPeerConnection, channels: DataChannels, restart_timeout_ms: u32) {
+        let peer_clone = peer.clone();
+        let waiting = Rc::new(Cell::new(false));
+        let oniceconnectionstatechange_callback = Closure::<dyn FnMut()>::new(move || {
+            let state = peer_clone.ice_connection_state();
+            console_log!("WebRtcServer: ICE connection state changed to {:?}", state);
+            let interrupted = matches!(state, RtcIceConnectionState::Disconnected | RtcIceConnectionState::Failed);
+            if interrupted && !waiting.replace(true) {
+                let (peer, channels, waiting) = (peer_clone.clone(), channels.clone(), waiting.clone());
+                spawn_local(async move {
+                    sleep(restart_timeout_ms).await;
+                    waiting.set(false);
+                    if !matches!(peer.ice_connection_state(), RtcIceConnectionState::Connected | RtcIceConnectionState::Completed) {
+                        console_warn!("WebRtcServer: Connection didn't recover within {}ms, dropping client", restart_timeout_ms);
+                        channels.close();
+                    }
+                });
+            }
+        });
+        peer.set_oniceconnectionstatechange(Some(oniceconnectionstatechange_callback.as_ref().unchecked_ref()));
+        oniceconnectionstatechange_callback.forget();
+    }
 
-        Ok(data_channel)
+    // Refuse clients speaking another protocol version or without the password, before their packets reach renet
+    async fn handshake(channel: &mut SendRecvCallbackChannel, password: &Option<String>) -> Result<(), JsValue> {
+        let refusal = match channel.recv().await? {
+            HandshakeMessage::Hello { version, .. } if version != PROTOCOL_VERSION => {
+                channel.send(HandshakeMessage::Incompatible { version: PROTOCOL_VERSION })?;
+                format!("Incompatible client version {}, ours is {}", version, PROTOCOL_VERSION)
+            }
+            HandshakeMessage::Hello { password: given, .. } if password.is_some() && given != *password => {
+                channel.send(HandshakeMessage::WrongPassword)?;
+                "Wrong password".to_owned()
+            }
+            HandshakeMessage::Hello { .. } => {
+                channel.send(HandshakeMessage::Welcome)?;
+                return Ok(());
+            }
+            msg => return Err(JsValue::from_str(&format!("Expected hello, got {:?}", msg))),
+        };
+        // Give the reply time to arrive before the channels get closed
+        sleep(HANDSHAKE_LINGER_MS).await;
+        Err(JsValue::from_str(&refusal))
     }
 }
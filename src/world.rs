@@ -1,4 +1,6 @@
 use bevy::{prelude::*, utils::HashMap, sprite::{MaterialMesh2dBundle, Mesh2d}};
+use clap::Args;
+use rand::{rngs::StdRng, SeedableRng};
 
 use crate::Multiplayer;
 
@@ -14,6 +16,26 @@ impl Plugin for WorldPlugin {
     }
 }
 
+#[derive(Resource, Args, Clone, Debug, Default)]
+pub struct WorldConfig {
+    /// Seed for enemy spawns and movement, for reproducible worlds
+    #[arg(id = "world_seed", long = "seed", value_name = "SEED")]
+    pub seed: Option<u64>,
+}
+
+// Randomness of the simulation, only on the authoritative side
+#[derive(Resource, Deref, DerefMut)]
+pub struct WorldRng(StdRng);
+
+impl WorldRng {
+    pub fn new(seed: Option<u64>) -> WorldRng {
+        match seed {
+            Some(seed) => WorldRng(StdRng::seed_from_u64(seed)),
+            None => WorldRng(StdRng::from_entropy()),
+        }
+    }
+}
+
 #[derive(Component)]
 struct World {
     chunks: HashMap<ChunkCoord, Chunk>